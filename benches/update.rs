@@ -0,0 +1,47 @@
+//! Times one step of the Gravner-Griffeath update and the STL mesh of a grown crystal.
+//!
+//! Run with `cargo bench`; criterion compares each run with the previous one.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use snowflake_rs::bench::{
+    cells_to_triangles, Lattice, Relief, SeedPattern, SimulationConfigInner, State,
+};
+
+fn config() -> SimulationConfigInner {
+    SimulationConfigInner {
+        seed: 1,
+        ..Default::default()
+    }
+}
+
+fn update(c: &mut Criterion) {
+    let config = config();
+    let mut group = c.benchmark_group("update");
+    // 大きい格子は1ステップが重いので標本を減らす
+    group.sample_size(20);
+    for n in [256, 512, 1024] {
+        let mut state = State::<f32>::new(n, config.rho, config.seed(), &SeedPattern::default());
+        // 作業領域を確保しておく
+        state.update(config);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, _| {
+            b.iter(|| state.update(config))
+        });
+    }
+    group.finish();
+}
+
+fn triangles(c: &mut Criterion) {
+    let config = config();
+    let n = 256;
+    let mut state = State::<f32>::new(n, config.rho, config.seed(), &SeedPattern::default());
+    for _ in 0..1000 {
+        state.update(config);
+    }
+    let cells = state.cells();
+    c.bench_function("cells_to_triangles", |b| {
+        b.iter(|| cells_to_triangles(&cells, Lattice::Hex, 1.0, 1.0, Relief::default(), &|_| {}))
+    });
+}
+
+criterion_group!(benches, update, triangles);
+criterion_main!(benches);
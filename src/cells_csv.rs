@@ -0,0 +1,67 @@
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+use ndarray::Array2;
+
+/// Layout of the cell values in the CSV.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CellsCsvFormat {
+    /// `i,j,value` rows for the nonzero cells only
+    #[default]
+    Sparse,
+    /// one row of the grid per line, without a header
+    Dense,
+}
+
+/// Writes the crystal mass of the cells as CSV for offline analysis.
+pub fn write_cells_to_csv(
+    cells: &Array2<f32>,
+    path: &Path,
+    format: CellsCsvFormat,
+) -> std::io::Result<()> {
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    write_cells(file, cells, format)
+}
+
+fn write_cells(
+    writer: impl Write,
+    cells: &Array2<f32>,
+    format: CellsCsvFormat,
+) -> std::io::Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    match format {
+        CellsCsvFormat::Sparse => {
+            writer.write_record(["i", "j", "value"])?;
+            // ほとんどのセルは0なので書かない
+            for ((i, j), &value) in cells.indexed_iter().filter(|(_, value)| **value != 0.0) {
+                writer.write_record([i.to_string(), j.to_string(), value.to_string()])?;
+            }
+        }
+        CellsCsvFormat::Dense => {
+            for row in cells.rows() {
+                writer.write_record(row.iter().map(f32::to_string))?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::*;
+
+    #[test]
+    fn sparse_csv_lists_nonzero_cells() {
+        let cells = array![[0.0, 0.5], [0.0, 0.0], [1.25, 0.0]];
+        let mut sparse = Vec::new();
+        write_cells(&mut sparse, &cells, CellsCsvFormat::Sparse).unwrap();
+        assert_eq!(
+            String::from_utf8(sparse).unwrap(),
+            "i,j,value\n0,1,0.5\n2,0,1.25\n"
+        );
+        let mut dense = Vec::new();
+        write_cells(&mut dense, &cells, CellsCsvFormat::Dense).unwrap();
+        assert_eq!(String::from_utf8(dense).unwrap(), "0,0.5\n0,0\n1.25,0\n");
+    }
+}
@@ -0,0 +1,179 @@
+//! Two saved crystals loaded side by side, with their difference shown in the 2D view.
+
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use ndarray::Array2;
+
+use crate::{file_dialog, gravner_griffeath, npy, Field};
+
+/// The two crystals being compared and their difference.
+#[derive(Resource, Default)]
+pub struct Comparison {
+    /// show the difference instead of the running crystal in the 2D view
+    pub is_shown: bool,
+    a: Option<LoadedField>,
+    b: Option<LoadedField>,
+    /// `b - a` scaled into -1..=1, when both are loaded with the same size
+    difference: Option<Array2<f32>>,
+    /// largest absolute difference before scaling
+    max_difference: f32,
+}
+
+struct LoadedField {
+    path: PathBuf,
+    cells: Array2<f32>,
+}
+
+impl Comparison {
+    /// The scaled difference to draw, if it is to be shown.
+    pub fn shown_difference(&self) -> Option<&Array2<f32>> {
+        self.difference.as_ref().filter(|_| self.is_shown)
+    }
+
+    fn update_difference(&mut self) {
+        let (Some(a), Some(b)) = (&self.a, &self.b) else {
+            self.difference = None;
+            return;
+        };
+        if a.cells.dim() != b.cells.dim() {
+            self.difference = None;
+            return;
+        }
+        let difference = &b.cells - &a.cells;
+        self.max_difference = difference.fold(0.0f32, |max, &d| max.max(d.abs()));
+        let scale = if self.max_difference > 0.0 {
+            1.0 / self.max_difference
+        } else {
+            0.0
+        };
+        // どちらにも結晶のないセルは NaN にして描かない
+        self.difference = Some(
+            ndarray::Zip::from(&difference)
+                .and(&a.cells)
+                .and(&b.cells)
+                .map_collect(|&d, &a, &b| {
+                    if a > 0.0 || b > 0.0 {
+                        d * scale
+                    } else {
+                        f32::NAN
+                    }
+                }),
+        );
+    }
+}
+
+/// Reads the cells of a `.npy` array or a Gravner-Griffeath state file.
+fn load_cells(path: &Path) -> std::io::Result<Array2<f32>> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("npy") => npy::read_npy(path),
+        _ => gravner_griffeath::load_cells(path),
+    }
+}
+
+pub fn configure_ui(
+    mut contexts: EguiContexts,
+    field: Res<Field>,
+    mut comparison: ResMut<Comparison>,
+    // ファイルダイアログはメインスレッドで開く
+    _main_thread: Option<NonSend<bevy::core::NonSendMarker>>,
+) {
+    // 2D表示が描き直すのは、実際に変えたときだけにする
+    let mut touched = false;
+    egui::Window::new("Compare")
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            let comparison = comparison.bypass_change_detection();
+            let mut changed = false;
+            for (label, slot) in [("A", &mut comparison.a), ("B", &mut comparison.b)] {
+                ui.horizontal(|ui| {
+                    ui.label(label);
+                    if ui.button("Load…").clicked() {
+                        let current = slot.as_ref().map(|loaded| loaded.path.as_path());
+                        if let Some(path) = file_dialog::pick_open_path(current, &["npy", "state"])
+                        {
+                            match load_cells(&path) {
+                                Ok(cells) => {
+                                    *slot = Some(LoadedField { path, cells });
+                                    changed = true;
+                                }
+                                Err(e) => tracing::error!(
+                                    "Failed to load {} for comparison: {e}",
+                                    path.display()
+                                ),
+                            }
+                        }
+                    }
+                    if let Some(loaded) = slot {
+                        let name = loaded.path.file_name().unwrap_or_default();
+                        let [n, m] = [loaded.cells.nrows(), loaded.cells.ncols()];
+                        ui.label(format!("{} ({n}×{m})", name.to_string_lossy()));
+                    }
+                });
+            }
+            if changed {
+                comparison.update_difference();
+                touched = true;
+            }
+            let n = field.0.read().cells.nrows();
+            match (&comparison.a, &comparison.b, &comparison.difference) {
+                (Some(_), Some(_), None) => {
+                    ui.colored_label(egui::Color32::RED, "A and B differ in size");
+                }
+                (_, _, Some(difference)) => {
+                    touched |= ui
+                        .checkbox(&mut comparison.is_shown, "show B − A in the 2D view")
+                        .changed();
+                    ui.label(format!(
+                        "red: B larger, blue: A larger, up to {:.3}",
+                        comparison.max_difference
+                    ));
+                    if difference.nrows() != n {
+                        ui.colored_label(
+                            egui::Color32::YELLOW,
+                            format!(
+                                "The grid is {n}×{n}; set n to {} and reset to see all of it",
+                                difference.nrows()
+                            ),
+                        );
+                    }
+                }
+                _ => {
+                    ui.label("Load two .npy or state files");
+                }
+            }
+        });
+    if touched {
+        comparison.set_changed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn difference_is_scaled_and_skips_empty_cells() {
+        let mut comparison = Comparison {
+            a: Some(LoadedField {
+                path: PathBuf::new(),
+                cells: ndarray::array![[0.0, 1.0], [2.0, 1.0]],
+            }),
+            b: Some(LoadedField {
+                path: PathBuf::new(),
+                cells: ndarray::array![[0.0, 3.0], [0.0, 1.0]],
+            }),
+            ..Default::default()
+        };
+        comparison.update_difference();
+        assert_eq!(comparison.max_difference, 2.0);
+        let difference = comparison.difference.as_ref().unwrap();
+        assert!(difference[[0, 0]].is_nan());
+        assert_eq!(difference[[0, 1]], 1.0);
+        assert_eq!(difference[[1, 0]], -1.0);
+        assert_eq!(difference[[1, 1]], 0.0);
+        // 表示を選ぶまでは描かない
+        assert!(comparison.shown_difference().is_none());
+    }
+}
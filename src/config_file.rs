@@ -0,0 +1,111 @@
+//! JSON files with the grid size and the Gravner-Griffeath parameters of a run.
+
+use std::{fs::File, io::BufReader, path::Path};
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    fs::OpenOptions,
+    io::{BufWriter, Write as _},
+};
+
+use crate::{
+    gravner_griffeath::{Parameter, SimulationConfigInner},
+    DEFAULT_GRID_SIZE,
+};
+
+/// Grid sizes accepted from a config or state file, the same as the "n" box of the Control
+/// window.
+pub const GRID_SIZES: std::ops::RangeInclusive<usize> = 16..=2000;
+
+/// Contents of a config file.
+///
+/// The parameters are stored next to `n` at the top level; missing ones take their defaults.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ConfigFile {
+    /// grid size
+    #[serde(default = "default_grid_size")]
+    pub n: usize,
+    #[serde(flatten)]
+    pub parameters: SimulationConfigInner,
+}
+
+fn default_grid_size() -> usize {
+    DEFAULT_GRID_SIZE
+}
+
+impl ConfigFile {
+    /// Reads a config file, rejecting grid sizes and parameters out of range.
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let file: Self = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        file.validate()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(file)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !GRID_SIZES.contains(&self.n) {
+            return Err(format!(
+                "n = {} is outside {}..={}",
+                self.n,
+                GRID_SIZES.start(),
+                GRID_SIZES.end()
+            ));
+        }
+        for parameter in Parameter::ALL {
+            let (value, max) = (parameter.get(&self.parameters), parameter.max());
+            // NaN もここで弾く
+            if !(value.is_finite() && (0.0..=max).contains(&value)) {
+                return Err(format!(
+                    "{} = {value} is outside 0..={max}",
+                    parameter.name()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_file_round_trips_and_fills_defaults() {
+        let path =
+            std::env::temp_dir().join(format!("snowflake-test-config-{}.json", std::process::id()));
+        let file = ConfigFile {
+            n: 300,
+            parameters: SimulationConfigInner {
+                rho: 0.6,
+                kappa: 0.002,
+                ..Default::default()
+            },
+        };
+        file.save(&path).unwrap();
+        let loaded = ConfigFile::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), file);
+
+        let partial: ConfigFile = serde_json::from_str(r#"{"beta": 1.6}"#).unwrap();
+        assert_eq!(partial.n, DEFAULT_GRID_SIZE);
+        assert_eq!(partial.parameters.beta, 1.6);
+        assert_eq!(partial.parameters.rho, SimulationConfigInner::default().rho);
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        let file: ConfigFile = serde_json::from_str(r#"{"n": 8}"#).unwrap();
+        assert!(file.validate().is_err());
+        let file: ConfigFile = serde_json::from_str(r#"{"kappa": 1.5}"#).unwrap();
+        assert!(file.validate().is_err());
+        let file: ConfigFile = serde_json::from_str(r#"{"rho": -0.1}"#).unwrap();
+        assert!(file.validate().is_err());
+    }
+}
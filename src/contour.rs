@@ -0,0 +1,255 @@
+use fnv::{FnvHashMap, FnvHashSet};
+use ndarray::Array2;
+
+/// Extracts the outlines of the region where `mask` is true.
+///
+/// The region is the union of the lattice triangles whose three cells are all true.
+/// Each contour is a simple closed loop of cell indices, with the first point repeated at
+/// the end. Outlines that touch at a single point are split there into separate loops.
+pub fn extract_contours(mask: &Array2<bool>) -> Vec<Vec<(usize, usize)>> {
+    let n = mask.shape()[0];
+    // 辺の集合。始点をキーとして終点の集合を値とする。
+    let mut segments: FnvHashMap<(usize, usize), FnvHashSet<(usize, usize)>> =
+        FnvHashMap::default();
+    let directions0 = [(0, 0), (1, 0), (0, 1)];
+    let directions1 = [(1, 0), (1, 1), (0, 1)];
+
+    for i in 0..(n - 1) {
+        for j in 0..(n - 1) {
+            let i1 = i + 1;
+            let j1 = j + 1;
+            if mask[[i, j]] && mask[[i1, j]] && mask[[i, j1]] {
+                for k in 0..3 {
+                    let start = (i + directions0[k].0, j + directions0[k].1);
+                    let end = (
+                        i + directions0[(k + 1) % 3].0,
+                        j + directions0[(k + 1) % 3].1,
+                    );
+                    // 逆向きの辺がすでに追加されていたら打ち消して終了、そうでなければ追加
+                    if let Some(end_set) = segments.get_mut(&end) {
+                        if !end_set.remove(&start) {
+                            segments.entry(start).or_default().insert(end);
+                        } else if end_set.is_empty() {
+                            segments.remove(&end);
+                        }
+                    } else {
+                        segments.entry(start).or_default().insert(end);
+                    }
+                }
+            }
+            if mask[[i1, j]] && mask[[i1, j1]] && mask[[i, j1]] {
+                for k in 0..3 {
+                    let start = (i + directions1[k].0, j + directions1[k].1);
+                    let end = (
+                        i + directions1[(k + 1) % 3].0,
+                        j + directions1[(k + 1) % 3].1,
+                    );
+                    if let Some(end_set) = segments.get_mut(&end) {
+                        if !end_set.remove(&start) {
+                            segments.entry(start).or_default().insert(end);
+                        } else if end_set.is_empty() {
+                            segments.remove(&end);
+                        }
+                    } else {
+                        segments.entry(start).or_default().insert(end);
+                    }
+                }
+            }
+        }
+    }
+
+    // 輪郭抽出。辺をたどって、たどってきた経路上の点に戻ったらそこまでを1つの輪郭として切り出す。
+    // こうすると複数の輪郭が1点で接していても、どの輪郭も同じ点を2度通らない
+    let mut contours = Vec::new();
+    while let Some(&start) = segments.keys().next() {
+        let mut path = vec![start];
+        let mut indices = FnvHashMap::default();
+        indices.insert(start, 0);
+        while let Some(&current) = path.last() {
+            let Some(next_set) = segments.get_mut(&current) else {
+                break;
+            };
+            let next = match path.len().checked_sub(2).map(|k| path[k]) {
+                Some(previous) if next_set.len() > 1 => {
+                    sharpest_left_turn(previous, current, next_set)
+                }
+                _ => *next_set.iter().next().unwrap(),
+            };
+            // 使った辺だけを取り除く。点から複数の辺が出ている場合に他の辺を失わないように
+            next_set.remove(&next);
+            if next_set.is_empty() {
+                segments.remove(&current);
+            }
+            if let Some(&k) = indices.get(&next) {
+                let mut contour = path.split_off(k);
+                for point in &contour[1..] {
+                    indices.remove(point);
+                }
+                contour.push(next);
+                contours.push(contour);
+                if k == 0 {
+                    break;
+                }
+                path.push(next);
+            } else {
+                indices.insert(next, path.len());
+                path.push(next);
+            }
+        }
+    }
+    contours
+}
+
+/// Chooses the outgoing edge that turns most to the left after `previous` → `current`.
+///
+/// Outlines keep the region on their left, so hugging it separates outlines that touch
+/// at a single point into simple loops.
+fn sharpest_left_turn(
+    previous: (usize, usize),
+    current: (usize, usize),
+    candidates: &FnvHashSet<(usize, usize)>,
+) -> (usize, usize) {
+    let (px, py) = position(previous);
+    let (cx, cy) = position(current);
+    let incoming = (cy - py).atan2(cx - px);
+    *candidates
+        .iter()
+        .max_by(|&&a, &&b| {
+            let turn = |(x, y): (f32, f32)| {
+                let angle = (y - cy).atan2(x - cx) - incoming;
+                // (-π, π] に収める
+                let angle = angle.rem_euclid(std::f32::consts::TAU);
+                if angle > std::f32::consts::PI {
+                    angle - std::f32::consts::TAU
+                } else {
+                    angle
+                }
+            };
+            turn(position(a)).total_cmp(&turn(position(b)))
+        })
+        .unwrap()
+}
+
+/// Position of a lattice point on the plane.
+fn position((i, j): (usize, usize)) -> (f32, f32) {
+    (i as f32 + j as f32 * 0.5, j as f32 * 3.0f32.sqrt() / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use fnv::FnvHashSet;
+    use ndarray::Array2;
+
+    use super::*;
+
+    fn assert_simple_closed(contour: &[(usize, usize)]) {
+        assert_eq!(contour.first(), contour.last(), "not closed: {contour:?}");
+        let points: FnvHashSet<_> = contour[1..].iter().collect();
+        assert_eq!(points.len(), contour.len() - 1, "not simple: {contour:?}");
+    }
+
+    /// The loop without its repeated end point, rotated to begin at `point`.
+    fn starting_at(contour: &[(usize, usize)], point: (usize, usize)) -> Vec<(usize, usize)> {
+        let points = &contour[..contour.len() - 1];
+        let k = points.iter().position(|&p| p == point).unwrap();
+        [&points[k..], &points[..k]].concat()
+    }
+
+    /// Signed area of the polygon through `points` on the plane, positive when counter-clockwise.
+    fn signed_area(points: &[(usize, usize)]) -> f32 {
+        let positions: Vec<_> = points.iter().map(|&p| position(p)).collect();
+        let doubled: f32 = (0..positions.len())
+            .map(|k| {
+                let (x0, y0) = positions[k];
+                let (x1, y1) = positions[(k + 1) % positions.len()];
+                x0 * y1 - x1 * y0
+            })
+            .sum();
+        doubled / 2.0
+    }
+
+    #[test]
+    fn single_cell_has_no_outline() {
+        // 三角形を作れないので輪郭もない
+        let mut mask = Array2::from_elem((5, 5), false);
+        mask[[2, 2]] = true;
+        assert!(extract_contours(&mask).is_empty());
+    }
+
+    #[test]
+    fn disconnected_blobs() {
+        let mask = Array2::from_shape_fn((12, 12), |(i, j)| {
+            let (q, r) = (i as isize - 3, j as isize - 3);
+            let (q2, r2) = (i as isize - 8, j as isize - 8);
+            q.abs().max(r.abs()).max((q + r).abs()) <= 1
+                || q2.abs().max(r2.abs()).max((q2 + r2).abs()) <= 1
+        });
+        let contours = extract_contours(&mask);
+        assert_eq!(contours.len(), 2);
+        for contour in &contours {
+            assert_eq!(contour.len(), 6 + 1);
+            assert_simple_closed(contour);
+        }
+    }
+
+    #[test]
+    fn ring_has_outline_and_hole() {
+        // 半径2の六角形から中心を抜く
+        let mut mask = Array2::from_shape_fn((9, 9), |(i, j)| {
+            let (q, r) = (i as isize - 4, j as isize - 4);
+            q.abs().max(r.abs()).max((q + r).abs()) <= 2
+        });
+        mask[[4, 4]] = false;
+        let mut contours = extract_contours(&mask);
+        contours.sort_by_key(Vec::len);
+        assert_eq!(contours.len(), 2);
+        assert_eq!(contours[0].len(), 6 + 1);
+        assert_eq!(contours[1].len(), 12 + 1);
+        for contour in &contours {
+            assert_simple_closed(contour);
+        }
+    }
+
+    #[test]
+    fn hole_touching_outline() {
+        // (3, 4)を抜いた穴が、(4, 3)と(4, 4)の2点で外周と接する
+        let mut mask = Array2::from_shape_fn((9, 9), |(i, j)| {
+            (1..=4).contains(&i) && (1..=7).contains(&j)
+        });
+        mask[[5, 3]] = true;
+        mask[[3, 4]] = false;
+        let mut contours = extract_contours(&mask);
+        contours.sort_by_key(Vec::len);
+        assert_eq!(contours.len(), 2);
+        for contour in &contours {
+            assert_simple_closed(contour);
+        }
+        // 下に突き出た三角形は接点で切り離される
+        let triangle = starting_at(&contours[0], (4, 3));
+        assert_eq!(triangle, [(4, 3), (5, 3), (4, 4)]);
+        assert!(signed_area(&triangle) > 0.0);
+        // 穴は接点で外に開いているので外周の一部としてたどられる。外周は反時計回り、穴の部分は時計回り
+        let outline = starting_at(&contours[1], (4, 3));
+        assert_eq!(outline.len(), 22);
+        let hole = &outline[..6];
+        assert_eq!(hole, [(4, 3), (3, 3), (2, 4), (2, 5), (3, 5), (4, 4)]);
+        assert!(signed_area(hole) < 0.0);
+        // 3×6の菱形の平行四辺形から穴の六角形を除いた面積
+        let unit = 3.0f32.sqrt() / 4.0;
+        assert!((signed_area(&outline) - (36.0 - 6.0) * unit).abs() < 1e-3);
+    }
+
+    #[test]
+    fn triangles_touching_at_a_point() {
+        let mut mask = Array2::from_elem((7, 7), false);
+        for (i, j) in [(3, 3), (4, 3), (3, 4), (2, 3), (3, 2)] {
+            mask[[i, j]] = true;
+        }
+        let contours = extract_contours(&mask);
+        assert_eq!(contours.len(), 2);
+        for contour in &contours {
+            assert_eq!(contour.len(), 4);
+            assert_simple_closed(contour);
+        }
+    }
+}
@@ -0,0 +1,23 @@
+//! Saving files in the browser, which has no filesystem to write them to.
+
+/// Lets the browser download `bytes` as a file named `name`.
+pub fn download(name: &str, bytes: &[u8]) -> std::io::Result<()> {
+    download_blob(name, bytes).map_err(|e| std::io::Error::other(format!("{e:?}")))
+}
+
+fn download_blob(name: &str, bytes: &[u8]) -> Result<(), wasm_bindgen::JsValue> {
+    use wasm_bindgen::JsCast as _;
+
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(bytes));
+    let blob = web_sys::Blob::new_with_u8_array_sequence(&parts)?;
+    let url = web_sys::Url::create_object_url_with_blob(&blob)?;
+    let document = web_sys::window()
+        .and_then(|window| window.document())
+        .ok_or("no document to download from")?;
+    // リンクを作ってクリックすると、ブラウザが保存先を聞くかダウンロードフォルダに保存する
+    let anchor: web_sys::HtmlAnchorElement = document.create_element("a")?.dyn_into()?;
+    anchor.set_href(&url);
+    anchor.set_download(name);
+    anchor.click();
+    web_sys::Url::revoke_object_url(&url)
+}
@@ -0,0 +1,143 @@
+//! Native dialogs to choose where files are saved and which files are loaded.
+//!
+//! The dialogs block the thread they run on, so the systems using them have to run on the main
+//! thread (see [`bevy::core::NonSendMarker`]).
+
+use std::path::{Path, PathBuf};
+#[cfg(not(target_arch = "wasm32"))]
+use std::{fs::OpenOptions, io::Write as _};
+
+use crate::OutputDirectory;
+
+/// Asks where to save a file, suggesting `name` in the output directory.
+///
+/// Returns `None` if the dialog is cancelled.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn pick_save_path(directory: &OutputDirectory, name: &str) -> Option<PathBuf> {
+    with_filter(rfd::FileDialog::new(), name)
+        .set_directory(&directory.0)
+        .set_file_name(name)
+        .save_file()
+}
+
+// ブラウザではダイアログで保存先を選べないので、出力ディレクトリの既定の名前を使う
+#[cfg(target_arch = "wasm32")]
+pub fn pick_save_path(directory: &OutputDirectory, name: &str) -> Option<PathBuf> {
+    Some(directory.0.join(name))
+}
+
+/// Asks for a file to load, starting in the directory of `current` if given.
+///
+/// `extensions` restrict the files shown. Returns `None` if the dialog is cancelled.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn pick_open_path(current: Option<&Path>, extensions: &[&str]) -> Option<PathBuf> {
+    let mut dialog = rfd::FileDialog::new().add_filter(extensions.join(", "), extensions);
+    if let Some(dir) = current.and_then(Path::parent) {
+        dialog = dialog.set_directory(dir);
+    }
+    dialog.pick_file()
+}
+
+// ブラウザのファイルはパスを持たないので読み込めない
+#[cfg(target_arch = "wasm32")]
+pub fn pick_open_path(_current: Option<&Path>, _extensions: &[&str]) -> Option<PathBuf> {
+    None
+}
+
+/// Asks where to save a file with [`pick_save_path`] and writes it there with `write`, as in
+/// [`replace`].
+///
+/// Returns `None` if the dialog is cancelled.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn save_as(
+    directory: &OutputDirectory,
+    name: &str,
+    write: impl FnOnce(&Path) -> std::io::Result<()>,
+) -> Option<std::io::Result<PathBuf>> {
+    let path = pick_save_path(directory, name)?;
+    Some(replace(&path, write).map(|()| path))
+}
+
+/// Asks where to save a file with [`save_as`] and writes the contents `bytes` there.
+///
+/// Returns `None` if the dialog is cancelled.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn save_bytes_as(
+    directory: &OutputDirectory,
+    name: &str,
+    bytes: impl FnOnce() -> std::io::Result<Vec<u8>>,
+) -> Option<std::io::Result<PathBuf>> {
+    save_as(directory, name, |path| {
+        let bytes = bytes()?;
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(&bytes)
+    })
+}
+
+// ブラウザはファイルに書けないので、`name` としてダウンロードさせる
+#[cfg(target_arch = "wasm32")]
+pub fn save_bytes_as(
+    _directory: &OutputDirectory,
+    name: &str,
+    bytes: impl FnOnce() -> std::io::Result<Vec<u8>>,
+) -> Option<std::io::Result<PathBuf>> {
+    let result = bytes().and_then(|bytes| crate::download::download(name, &bytes));
+    Some(result.map(|()| name.into()))
+}
+
+/// Writes `path`, whose overwriting the user has confirmed in the save dialog, with `write`.
+///
+/// `write` creates a new file, so it writes to a temporary file next to `path` that is then
+/// renamed over it. A failed write leaves the existing file as it was.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn replace(
+    path: &Path,
+    write: impl FnOnce(&Path) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".partial");
+    let temporary = path.with_file_name(name);
+    // 前回の書きかけが残っていれば消す
+    let _ = std::fs::remove_file(&temporary);
+    let result = write(&temporary).and_then(|()| std::fs::rename(&temporary, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+    result
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn with_filter(dialog: rfd::FileDialog, name: &str) -> rfd::FileDialog {
+    match Path::new(name).extension().and_then(|e| e.to_str()) {
+        Some(extension) => dialog.add_filter(extension, &[extension]),
+        None => dialog,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_replace_keeps_the_existing_file() {
+        let dir =
+            std::env::temp_dir().join(format!("snowflake-test-replace-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("crystal.stl");
+        std::fs::write(&path, "old").unwrap();
+
+        let result = replace(&path, |path| {
+            std::fs::write(path, "half")?;
+            Err(std::io::Error::other("disk full"))
+        });
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old");
+
+        replace(&path, |path| std::fs::write(path, "new")).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        // 書きかけのファイルは残らない
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
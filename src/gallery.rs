@@ -0,0 +1,183 @@
+//! Snapshots of the crystal taken every few steps, shown as a strip of thumbnails.
+
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use chrono::Local;
+use ndarray::Array2;
+
+use crate::{
+    file_dialog, file_name, gravner_griffeath::Lattice, png, stl, visualization::normalize,
+    ExportSettings, Field, OutputDirectory, SaveStatus,
+};
+
+/// Snapshots kept at most; each keeps a full copy of the cells for exporting.
+const MAX_SNAPSHOTS: usize = 12;
+/// Width of the thumbnails in pixels.
+const THUMBNAIL_WIDTH: f32 = 96.0;
+
+/// Snapshots of the current run, oldest first.
+#[derive(Resource)]
+pub struct SnapshotGallery {
+    pub is_enabled: bool,
+    /// take a snapshot every `interval` steps
+    pub interval: u64,
+    snapshots: VecDeque<Snapshot>,
+    last_captured: Option<u64>,
+    /// step of the snapshot chosen for exporting
+    selected: Option<u64>,
+}
+
+impl Default for SnapshotGallery {
+    fn default() -> Self {
+        Self {
+            is_enabled: false,
+            interval: 500,
+            snapshots: VecDeque::new(),
+            last_captured: None,
+            selected: None,
+        }
+    }
+}
+
+struct Snapshot {
+    step: u64,
+    cells: Array2<f32>,
+    lattice: Lattice,
+    thumbnail: egui::ColorImage,
+    /// uploaded when the thumbnail is first shown
+    texture: Option<egui::TextureHandle>,
+}
+
+impl Snapshot {
+    fn new(step: u64, cells: Array2<f32>, lattice: Lattice) -> Self {
+        // サムネイルの幅に縮める
+        let scale = THUMBNAIL_WIDTH / png::extent(cells.shape()[0].max(2), lattice).x;
+        let image = png::rasterize(&normalize(&cells), lattice, Some(scale));
+        let size = [image.width() as usize, image.height() as usize];
+        Self {
+            step,
+            thumbnail: egui::ColorImage::from_gray(size, image.as_raw()),
+            cells,
+            lattice,
+            texture: None,
+        }
+    }
+}
+
+pub fn capture_snapshots(field: Res<Field>, mut gallery: ResMut<SnapshotGallery>) {
+    if !gallery.is_enabled {
+        return;
+    }
+    // 読み取りロックは複製する間だけ取る
+    let (step, cells, lattice) = {
+        let field = field.0.read();
+        let due = match gallery.last_captured {
+            Some(last) => field.step >= last + gallery.interval || field.step < last,
+            None => true,
+        };
+        if !due {
+            return;
+        }
+        (field.step, field.cells.clone(), field.lattice)
+    };
+    // リセットされたら前の実行の分は捨てる
+    if gallery.last_captured.is_some_and(|last| step < last) {
+        gallery.snapshots.clear();
+        gallery.selected = None;
+    }
+    if gallery.snapshots.len() == MAX_SNAPSHOTS {
+        gallery.snapshots.pop_front();
+    }
+    gallery
+        .snapshots
+        .push_back(Snapshot::new(step, cells, lattice));
+    gallery.last_captured = Some(step);
+}
+
+pub fn configure_ui(
+    mut contexts: EguiContexts,
+    mut gallery: ResMut<SnapshotGallery>,
+    export_settings: Res<ExportSettings>,
+    output_directory: Res<OutputDirectory>,
+    mut save_status: ResMut<SaveStatus>,
+    // ファイルダイアログはメインスレッドで開く
+    _main_thread: Option<NonSend<bevy::core::NonSendMarker>>,
+) {
+    egui::Window::new("Snapshots")
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            let gallery = &mut *gallery;
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut gallery.is_enabled, "capture");
+                ui.add(
+                    egui::DragValue::new(&mut gallery.interval)
+                        .range(1..=100000)
+                        .prefix("every ")
+                        .suffix(" steps"),
+                );
+                if ui.button("Clear").clicked() {
+                    gallery.snapshots.clear();
+                    gallery.selected = None;
+                }
+            });
+            egui::ScrollArea::horizontal().show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    for snapshot in &mut gallery.snapshots {
+                        let texture = snapshot.texture.get_or_insert_with(|| {
+                            ui.ctx().load_texture(
+                                format!("snapshot-{}", snapshot.step),
+                                snapshot.thumbnail.clone(),
+                                egui::TextureOptions::LINEAR,
+                            )
+                        });
+                        let selected = gallery.selected == Some(snapshot.step);
+                        let button = egui::ImageButton::new((texture.id(), texture.size_vec2()))
+                            .selected(selected);
+                        if ui
+                            .add(button)
+                            .on_hover_text(format!("Step {}", snapshot.step))
+                            .clicked()
+                        {
+                            gallery.selected = (!selected).then_some(snapshot.step);
+                        }
+                    }
+                });
+            });
+            let Some(snapshot) = gallery
+                .snapshots
+                .iter()
+                .find(|snapshot| Some(snapshot.step) == gallery.selected)
+            else {
+                return;
+            };
+            ui.horizontal(|ui| {
+                ui.label(format!("Step {}", snapshot.step));
+                if ui.button("Export PNG").clicked() {
+                    let name = file_name(Local::now(), "png");
+                    if let Some(result) =
+                        file_dialog::save_bytes_as(&output_directory, &name, || {
+                            png::png_bytes(&snapshot.cells, snapshot.lattice, None)
+                        })
+                    {
+                        save_status.report("PNG", result);
+                    }
+                }
+                if ui.button("Export STL").clicked() {
+                    let name = file_name(Local::now(), "stl");
+                    if let Some(result) =
+                        file_dialog::save_bytes_as(&output_directory, &name, || {
+                            stl::stl_bytes(
+                                &snapshot.cells,
+                                export_settings.stl_options(snapshot.lattice),
+                                &|_| {},
+                            )
+                        })
+                    {
+                        save_status.report("STL", result);
+                    }
+                }
+            });
+        });
+}
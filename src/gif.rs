@@ -0,0 +1,138 @@
+use std::{fs::OpenOptions, io::BufWriter, path::Path};
+
+use bevy::prelude::*;
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    Delay, DynamicImage, Frame, GrayImage,
+};
+use ndarray::Array2;
+
+use crate::{gravner_griffeath::Lattice, png::rasterize, visualization::normalize, Field};
+
+/// Delay between frames in hundredths of a second.
+const FRAME_DELAY: u32 = 10;
+
+/// Frames of the growth captured while recording.
+#[derive(Resource)]
+pub struct GifRecorder {
+    pub is_recording: bool,
+    /// capture a frame every `interval` steps
+    pub interval: u64,
+    /// stop capturing once this many frames are buffered
+    pub max_frames: usize,
+    frames: Vec<Array2<f32>>,
+    /// lattice of the frames, that of the field when the first one was captured
+    lattice: Lattice,
+    last_captured: Option<u64>,
+}
+
+impl Default for GifRecorder {
+    fn default() -> Self {
+        Self {
+            is_recording: false,
+            interval: 10,
+            max_frames: 200,
+            frames: Vec::new(),
+            lattice: Lattice::default(),
+            last_captured: None,
+        }
+    }
+}
+
+impl GifRecorder {
+    pub fn start(&mut self) {
+        self.frames.clear();
+        self.last_captured = None;
+        self.is_recording = true;
+    }
+
+    /// Stops recording and writes the buffered frames as an animated GIF.
+    pub fn stop(&mut self, path: &Path) -> std::io::Result<()> {
+        self.is_recording = false;
+        let frames: Vec<GrayImage> = self
+            .frames
+            .drain(..)
+            .map(|values| rasterize(&values, self.lattice, None))
+            .collect();
+        write_gif(&frames, path)
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+pub fn record_frames(field: Res<Field>, mut recorder: ResMut<GifRecorder>) {
+    if !recorder.is_recording || recorder.frames.len() >= recorder.max_frames {
+        return;
+    }
+    let field = field.0.read();
+    // 途中でグリッドサイズや格子が変わったフレームは混ぜない
+    if recorder
+        .frames
+        .first()
+        .is_some_and(|frame| frame.dim() != field.cells.dim() || recorder.lattice != field.lattice)
+    {
+        return;
+    }
+    let due = match recorder.last_captured {
+        Some(last) => field.step >= last + recorder.interval || field.step < last,
+        None => true,
+    };
+    if !due {
+        return;
+    }
+    recorder.lattice = field.lattice;
+    recorder.frames.push(normalize(&field.cells));
+    recorder.last_captured = Some(field.step);
+    if recorder.frames.len() == recorder.max_frames {
+        tracing::warn!("GIF recording reached {} frames", recorder.max_frames);
+    }
+}
+
+fn write_gif(frames: &[GrayImage], path: &Path) -> std::io::Result<()> {
+    if frames.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no frames were recorded",
+        ));
+    }
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let mut encoder = GifEncoder::new(BufWriter::new(file));
+    encoder
+        .set_repeat(Repeat::Infinite)
+        .map_err(std::io::Error::other)?;
+    let delay = Delay::from_numer_denom_ms(FRAME_DELAY * 10, 1);
+    encoder
+        .encode_frames(frames.iter().map(|frame| {
+            let frame = DynamicImage::ImageLuma8(frame.clone()).into_rgba8();
+            Frame::from_parts(frame, 0, 0, delay)
+        }))
+        .map_err(std::io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::BufReader};
+
+    use image::{codecs::gif::GifDecoder, AnimationDecoder as _};
+
+    use super::*;
+
+    #[test]
+    fn frames_are_read_back() {
+        let frames: Vec<GrayImage> = (0..3)
+            .map(|k| GrayImage::from_fn(8, 6, |x, y| image::Luma([(x + y) as u8 * k * 10])))
+            .collect();
+        let path =
+            std::env::temp_dir().join(format!("snowflake-test-gif-{}.gif", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        write_gif(&frames, &path).unwrap();
+
+        let decoder = GifDecoder::new(BufReader::new(File::open(&path).unwrap())).unwrap();
+        let decoded = decoder.into_frames().collect_frames().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(decoded.len(), frames.len());
+        assert_eq!(decoded[0].buffer().dimensions(), (8, 6));
+    }
+}
@@ -0,0 +1,214 @@
+// Gravner-Griffeath の1ステップ。gravner_griffeath.rs の State::update と同じ計算をする。
+// セルは (a, b, c, d) を vec4 に詰め、a は凍結していれば 1.0 とする。
+
+struct Params {
+    n: u32,
+    boundary: u32,
+    noise_seed: u32,
+    has_noise: u32,
+    rho: f32,
+    beta: f32,
+    alpha: f32,
+    theta: f32,
+    kappa: f32,
+    mu: f32,
+    gamma: f32,
+    sigma: f32,
+    diffusion_weight: f32,
+    weight_sum: f32,
+    replenish_rate: f32,
+    // 方向の重み 0°, 60°, …, 300°
+    weights_0: vec4<f32>,
+    weights_1: vec4<f32>,
+}
+
+// BoundaryCondition の並びと合わせる
+const PERIODIC: u32 = 0u;
+const CONSTANT: u32 = 1u;
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> cells_old: array<vec4<f32>>;
+@group(0) @binding(2) var<storage, read_write> cells_new: array<vec4<f32>>;
+@group(0) @binding(3) var<storage, read_write> neighbors: array<u32>;
+
+var<private> DIRECTIONS: array<vec2<i32>, 6> = array<vec2<i32>, 6>(
+    vec2<i32>(1, 0),
+    vec2<i32>(0, 1),
+    vec2<i32>(-1, 1),
+    vec2<i32>(-1, 0),
+    vec2<i32>(0, -1),
+    vec2<i32>(1, -1),
+);
+
+fn weight(k: u32) -> f32 {
+    if k < 4u {
+        return params.weights_0[k];
+    }
+    return params.weights_1[k - 4u];
+}
+
+// (i, j) から方向 k の近傍の添字。格子の外なら -1
+fn neighbor(i: i32, j: i32, k: u32) -> i32 {
+    let n = i32(params.n);
+    var ni = i + DIRECTIONS[k].x;
+    var nj = j + DIRECTIONS[k].y;
+    if params.boundary == PERIODIC {
+        ni = (ni + n) % n;
+        nj = (nj + n) % n;
+    } else if ni < 0 || ni >= n || nj < 0 || nj >= n {
+        return -1;
+    }
+    return ni * n + nj;
+}
+
+fn is_frozen(cell: vec4<f32>) -> bool {
+    return cell.x > 0.5;
+}
+
+// 整数のハッシュ (PCG)
+fn pcg(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// (i) Diffusion と (ii) Freezing
+@compute @workgroup_size(16, 16)
+fn diffuse_and_freeze(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.n || id.y >= params.n {
+        return;
+    }
+    let i = i32(id.x);
+    let j = i32(id.y);
+    let index = i * i32(params.n) + j;
+    let cell = cells_old[index];
+
+    var count = 0u;
+    for (var k = 0u; k < 6u; k++) {
+        let p = neighbor(i, j, k);
+        if p >= 0 && is_frozen(cells_old[p]) {
+            count += 1u;
+        }
+    }
+    neighbors[index] = count;
+
+    var b = cell.y;
+    var c = cell.z;
+    var d = 0.0;
+    if !is_frozen(cell) {
+        var sum = params.diffusion_weight * cell.w;
+        for (var k = 0u; k < 6u; k++) {
+            // 凍結した近傍や反射境界の外には送れない
+            let reflected = weight((k + 3u) % 6u) * cell.w;
+            let p = neighbor(i, j, k);
+            if p >= 0 {
+                if is_frozen(cells_old[p]) {
+                    sum += reflected;
+                } else {
+                    sum += weight(k) * cells_old[p].w;
+                }
+            } else if params.boundary == CONSTANT {
+                sum += weight(k) * params.rho;
+            } else {
+                sum += reflected;
+            }
+        }
+        if params.weight_sum > 0.0 {
+            d = sum / params.weight_sum;
+        } else {
+            d = cell.w;
+        }
+        if count > 0u {
+            b += (1.0 - params.kappa) * d;
+            c += params.kappa * d;
+            d = 0.0;
+        }
+    }
+    cells_new[index] = vec4<f32>(cell.x, b, c, d);
+}
+
+// (iii) Attachment。近傍から読まれる d には書き込まない
+@compute @workgroup_size(16, 16)
+fn attach(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.n || id.y >= params.n {
+        return;
+    }
+    let i = i32(id.x);
+    let j = i32(id.y);
+    let index = i * i32(params.n) + j;
+    let count = neighbors[index];
+    if is_frozen(cells_old[index]) || count == 0u {
+        return;
+    }
+
+    let cell = cells_new[index];
+    var attached = true;
+    if count <= 2u {
+        attached = cell.y >= params.beta;
+    } else if count == 3u {
+        var sum = 0.0;
+        for (var k = 0u; k < 6u; k++) {
+            let p = neighbor(i, j, k);
+            if p >= 0 {
+                sum += cells_new[p].w;
+            } else if params.boundary == CONSTANT {
+                sum += params.rho;
+            } else {
+                sum += cell.w;
+            }
+        }
+        attached = cell.y >= 1.0 || (cell.y >= params.alpha && sum < params.theta);
+    }
+
+    if attached {
+        cells_new[index].x = 1.0;
+        cells_new[index].z = cell.z + cell.y;
+        cells_new[index].y = 0.0;
+    }
+}
+
+// (iv) Melting と (v) Noise。近傍から読まれる a には書き込まない
+@compute @workgroup_size(16, 16)
+fn melt_and_add_noise(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.n || id.y >= params.n {
+        return;
+    }
+    let i = i32(id.x);
+    let j = i32(id.y);
+    let index = i * i32(params.n) + j;
+    let cell = cells_new[index];
+
+    var b = cell.y;
+    var c = cell.z;
+    var d = cell.w;
+    if !is_frozen(cell) {
+        var on_crystal_boundary = false;
+        for (var k = 0u; k < 6u; k++) {
+            let p = neighbor(i, j, k);
+            if p >= 0 && is_frozen(cells_new[p]) {
+                on_crystal_boundary = true;
+            }
+        }
+        if on_crystal_boundary {
+            let mu_b = params.mu * b;
+            let gamma_c = params.gamma * c;
+            b -= mu_b;
+            c -= gamma_c;
+            d += mu_b + gamma_c;
+        } else {
+            // 結晶から離れた蒸気を ρ に近づける
+            d += params.replenish_rate * (params.rho - d);
+        }
+    }
+    if params.has_noise != 0u {
+        if (pcg(u32(index) ^ pcg(params.noise_seed)) & 1u) != 0u {
+            d *= 1.0 + params.sigma;
+        } else {
+            d *= 1.0 - params.sigma;
+        }
+    }
+
+    cells_new[index].y = b;
+    cells_new[index].z = c;
+    cells_new[index].w = d;
+}
@@ -0,0 +1,336 @@
+//! Optional GPU backend of the Gravner-Griffeath update, enabled with the `gpu` feature.
+//!
+//! The update runs as three compute passes of `gravner_griffeath.wgsl` on a device of its own,
+//! driven from the simulation worker thread. The fields stay in two storage buffers on the GPU,
+//! which take turns as the input and the output of a step, and are copied only when the CPU
+//! needs them: uploaded after they were edited on the CPU, and read back for the view, the
+//! checks of the step and the saves.
+//!
+//! The device is separate from the one Bevy renders with, rather than a node in its render
+//! graph: the worker steps the simulation as fast as it can, independently of the frame rate,
+//! pauses and stops on its own rules, and also runs headless without a renderer at all. Nodes of
+//! the render graph would run once per frame in the render world, tying the step rate to the
+//! display and the checks of each step to a round trip through the extracted resources. The
+//! price is that the fields cannot be shared with the renderer: the view is drawn from the read
+//! back copy, and the device holds buffers of its own on the GPU, 52 bytes per cell for the two
+//! copies of the cells, the staging copy and the frozen neighbor counts.
+
+use std::sync::mpsc;
+
+use ndarray::Array2;
+
+use crate::gravner_griffeath::SimulationConfigInner;
+
+/// Size of the workgroups in the shader along each axis.
+const WORKGROUP_SIZE: u32 = 16;
+/// Bytes of a cell packed as `vec4<f32>(a, b, c, d)`.
+const CELL_SIZE: u64 = 16;
+/// Bytes of `Params` in the shader, including the padding before the weights.
+const PARAMS_SIZE: u64 = 96;
+
+pub struct GpuUpdater {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    layout: wgpu::BindGroupLayout,
+    /// diffuse_and_freeze, attach, melt_and_add_noise の順
+    pipelines: [wgpu::ComputePipeline; 3],
+    buffers: Option<Buffers>,
+}
+
+/// Buffers for an `n`×`n` grid.
+struct Buffers {
+    n: usize,
+    params: wgpu::Buffer,
+    /// fields packed as in [`CELL_SIZE`]; each step reads one and writes the other
+    cells: [wgpu::Buffer; 2],
+    /// index into `cells` of the latest fields
+    current: usize,
+    /// 付着の走査まで持ち越す凍結した近傍の数。バインドグループから使うだけ
+    _neighbors: wgpu::Buffer,
+    staging: wgpu::Buffer,
+    /// the bind group that reads `cells[k]` and writes the other one at `k`
+    bind_groups: [wgpu::BindGroup; 2],
+}
+
+impl GpuUpdater {
+    /// Opens the default adapter and compiles the shader.
+    pub fn new() -> Result<Self, String> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter =
+            bevy::tasks::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                ..Default::default()
+            }))
+            .ok_or("no GPU adapter found")?;
+        let (device, queue) =
+            bevy::tasks::block_on(adapter.request_device(&wgpu::DeviceDescriptor::default(), None))
+                .map_err(|e| e.to_string())?;
+        tracing::info!("GPU backend: {}", adapter.get_info().name);
+
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("gravner_griffeath"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("gravner_griffeath.wgsl").into()),
+        });
+        let storage = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("gravner_griffeath"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage(1, true),
+                storage(2, false),
+                storage(3, false),
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("gravner_griffeath"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipelines = ["diffuse_and_freeze", "attach", "melt_and_add_noise"].map(|entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: &module,
+                entry_point,
+                compilation_options: Default::default(),
+            })
+        });
+
+        Ok(Self {
+            device,
+            queue,
+            layout,
+            pipelines,
+            buffers: None,
+        })
+    }
+
+    /// Copies the fields into the buffers on the GPU, replacing those there.
+    pub fn upload(&mut self, a: &Array2<bool>, b: &Array2<f32>, c: &Array2<f32>, d: &Array2<f32>) {
+        let n = a.nrows();
+        if self.buffers.as_ref().map(|buffers| buffers.n) != Some(n) {
+            self.buffers = Some(self.create_buffers(n));
+        }
+        let buffers = self.buffers.as_ref().unwrap();
+        let mut cells = Vec::with_capacity(n * n * CELL_SIZE as usize);
+        for ((&a, &b), (&c, &d)) in a.iter().zip(b.iter()).zip(c.iter().zip(d.iter())) {
+            for value in [if a { 1.0f32 } else { 0.0 }, b, c, d] {
+                cells.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        self.queue
+            .write_buffer(&buffers.cells[buffers.current], 0, &cells);
+    }
+
+    /// Advances the fields on the GPU by one step, without waiting for it to finish.
+    ///
+    /// `noise_seed` replaces the CPU generator, so the noise differs from the CPU update; without
+    /// noise the results agree up to rounding.
+    pub fn step(&mut self, config: &SimulationConfigInner, noise_seed: u32) -> Result<(), String> {
+        let buffers = self
+            .buffers
+            .as_mut()
+            .ok_or("no fields have been uploaded")?;
+        let n = buffers.n;
+        self.queue
+            .write_buffer(&buffers.params, 0, &params_bytes(config, n, noise_seed));
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+            pass.set_bind_group(0, &buffers.bind_groups[buffers.current], &[]);
+            let workgroups = (n as u32).div_ceil(WORKGROUP_SIZE);
+            // パスの間の同期は wgpu が取る
+            for pipeline in &self.pipelines {
+                pass.set_pipeline(pipeline);
+                pass.dispatch_workgroups(workgroups, workgroups, 1);
+            }
+        }
+        self.queue.submit(Some(encoder.finish()));
+        buffers.current = 1 - buffers.current;
+        Ok(())
+    }
+
+    /// Reads the latest fields back from the GPU, waiting for the steps submitted so far.
+    ///
+    /// The arrays are left untouched on error.
+    pub fn download(
+        &mut self,
+        a: &mut Array2<bool>,
+        b: &mut Array2<f32>,
+        c: &mut Array2<f32>,
+        d: &mut Array2<f32>,
+    ) -> Result<(), String> {
+        let buffers = self
+            .buffers
+            .as_ref()
+            .ok_or("no fields have been uploaded")?;
+        if buffers.n != a.nrows() {
+            return Err(format!(
+                "the fields on the GPU are {0}×{0}, not {1}×{1}",
+                buffers.n,
+                a.nrows()
+            ));
+        }
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        encoder.copy_buffer_to_buffer(
+            &buffers.cells[buffers.current],
+            0,
+            &buffers.staging,
+            0,
+            buffers.staging.size(),
+        );
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = buffers.staging.slice(..);
+        let (sender, receiver) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver
+            .recv()
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
+        {
+            let data = slice.get_mapped_range();
+            let mut values = data
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+            for (((a, b), c), d) in a
+                .iter_mut()
+                .zip(b.iter_mut())
+                .zip(c.iter_mut())
+                .zip(d.iter_mut())
+            {
+                *a = values.next().unwrap() > 0.5;
+                *b = values.next().unwrap();
+                *c = values.next().unwrap();
+                *d = values.next().unwrap();
+            }
+        }
+        buffers.staging.unmap();
+        Ok(())
+    }
+
+    fn create_buffers(&self, n: usize) -> Buffers {
+        let cells_size = (n * n) as u64 * CELL_SIZE;
+        let buffer = |label, size, usage| {
+            self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size,
+                usage,
+                mapped_at_creation: false,
+            })
+        };
+        let params = buffer(
+            "params",
+            PARAMS_SIZE,
+            wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        );
+        let cells = ["cells_0", "cells_1"].map(|label| {
+            buffer(
+                label,
+                cells_size,
+                wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::COPY_DST
+                    | wgpu::BufferUsages::COPY_SRC,
+            )
+        });
+        let neighbors = buffer("neighbors", (n * n) as u64 * 4, wgpu::BufferUsages::STORAGE);
+        let staging = buffer(
+            "staging",
+            cells_size,
+            wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        );
+        let bind_groups = [0, 1].map(|old| {
+            self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("gravner_griffeath"),
+                layout: &self.layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: params.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: cells[old].as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: cells[1 - old].as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: neighbors.as_entire_binding(),
+                    },
+                ],
+            })
+        });
+        Buffers {
+            n,
+            params,
+            cells,
+            current: 0,
+            _neighbors: neighbors,
+            staging,
+            bind_groups,
+        }
+    }
+}
+
+/// `Params` of the shader as little-endian bytes.
+fn params_bytes(config: &SimulationConfigInner, n: usize, noise_seed: u32) -> Vec<u8> {
+    let weight_sum = config.diffusion_weight + config.direction_weights.iter().sum::<f32>();
+    let mut bytes = Vec::with_capacity(PARAMS_SIZE as usize);
+    for value in [
+        n as u32,
+        config.boundary as u32,
+        noise_seed,
+        (config.sigma.abs() > 0.0) as u32,
+    ] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in [
+        config.rho,
+        config.beta,
+        config.alpha,
+        config.theta,
+        config.kappa,
+        config.mu,
+        config.gamma,
+        config.sigma,
+        config.diffusion_weight,
+        weight_sum,
+        config.replenish_rate,
+        // vec4 の境界に揃える
+        0.0,
+    ] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    for weight in config.direction_weights.into_iter().chain([0.0, 0.0]) {
+        bytes.extend_from_slice(&weight.to_le_bytes());
+    }
+    bytes
+}
@@ -1,261 +1,195 @@
 use bevy::prelude::*;
 use bevy_egui::{egui, EguiContexts};
-use ndarray::{Array2, Zip};
-use ndarray_rand::{rand_distr::Standard, RandomExt as _};
 
 use crate::{
-    gravner_griffeath::{SimulationConfigInner, SimulationConfigLogInner},
-    ControlEvent, Field,
+    crystal_radius, download, file_name,
+    gravner_griffeath::{
+        parameter_sliders, SeedPattern, SimulationConfigInner, SimulationConfigLogInner,
+        SimulationConfigLogRecord, State,
+    },
+    symmetry, ControlEvent, Field, SaveStatus,
 };
 
-pub struct GravnerGrifeeathSimulatorWasmPlugin;
+pub struct GravnerGrifeeathSimulatorWasmPlugin {
+    pub config: SimulationConfigInner,
+}
+
+/// Upper limit of the fixed update rate, since FixedUpdate may run several times per frame.
+const MAX_STEPS_PER_SECOND: f32 = 200.0;
 
 impl Plugin for GravnerGrifeeathSimulatorWasmPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<SimulationConfigInner>();
+        app.insert_resource(SimulationConfigInner {
+            steps_per_second: 50.0,
+            ..self.config
+        });
         app.init_resource::<SimulationConfigLogInner>();
-        app.init_resource::<State>();
+        app.init_resource::<SimulationState>();
         app.add_systems(Update, (event_listener, configure_ui));
         app.add_systems(FixedUpdate, update_simulation);
         app.insert_resource(Time::<Fixed>::from_hz(50f64));
+        app.add_systems(Update, update_timestep);
     }
 }
 
-#[derive(Default, Resource)]
-struct State {
-    a: Array2<bool>,
-    b: Array2<f32>,
-    c: Array2<f32>,
-    d: Array2<f32>,
-}
-
-impl State {
-    fn new(n: usize, rho: f32) -> Self {
-        let mut a = Array2::<bool>::default((n, n));
-        a[[n / 2, n / 2]] = true;
-
-        let b = Array2::<f32>::zeros((n, n));
-
-        let mut c = Array2::<f32>::zeros((n, n));
-        c[[n / 2, n / 2]] = 1.0;
-
-        let mut d = Array2::<f32>::ones((n, n)) * rho;
-        d[[n / 2, n / 2]] = 0.0;
-
-        Self { a, b, c, d }
-    }
-
-    fn update(&mut self, config: SimulationConfigInner) {
-        let SimulationConfigInner {
-            beta,
-            alpha,
-            theta,
-            kappa,
-            mu,
-            gamma,
-            sigma,
-            ..
-        } = config;
-        let n = self.a.shape()[0];
-
-        let neighbors = Zip::indexed(&self.a).map_collect(|(i, j), _| {
-            self.a[[(i + 1) % n, j]] as u8
-                + self.a[[(i + n - 1) % n, j]] as u8
-                + self.a[[i, (j + 1) % n]] as u8
-                + self.a[[i, (j + n - 1) % n]] as u8
-                + self.a[[(i + n - 1) % n, (j + 1) % n]] as u8
-                + self.a[[(i + 1) % n, (j + n - 1) % n]] as u8
-        });
-
-        // (i) Diffusion
-        let mut d_new = Array2::<f32>::zeros(self.d.raw_dim());
-        Zip::indexed(&mut d_new)
-            .and(&self.a)
-            .and(&self.d)
-            .and(&neighbors)
-            .for_each(|(i, j), d, &a_old, &d_old, &neighbors| {
-                if !a_old {
-                    *d = (d_old
-                        + self.d[[(i + 1) % n, j]]
-                        + self.d[[(i + n - 1) % n, j]]
-                        + self.d[[i, (j + 1) % n]]
-                        + self.d[[i, (j + n - 1) % n]]
-                        + self.d[[(i + n - 1) % n, (j + 1) % n]]
-                        + self.d[[(i + 1) % n, (j + n - 1) % n]]
-                        + neighbors as f32 * d_old)
-                        / 7.0;
-                }
-            });
-
-        // (ii) Freezing
-        let mut b_new = self.b.clone();
-        let mut c_new = self.c.clone();
-        Zip::from(&self.a)
-            .and(&mut b_new)
-            .and(&mut c_new)
-            .and(&mut d_new)
-            .and(&neighbors)
-            .for_each(|&a, b, c, d, &neighbors| {
-                if !a && neighbors > 0 {
-                    *b += (1.0 - kappa) * *d;
-                    *c += kappa * *d;
-                    *d = 0.0;
-                }
-            });
-
-        // (iii) Attachment
-        let mut a_new = self.a.clone();
-        Zip::indexed(&mut a_new)
-            .and(&mut b_new)
-            .and(&mut c_new)
-            .and(&self.a)
-            .and(&neighbors)
-            .for_each(|(i, j), a, b, c, &a_old, &neighbors| {
-                if a_old || neighbors == 0 {
-                    return;
-                }
-
-                *a = match neighbors {
-                    0 => panic!("not a boundary cell"),
-                    1..=2 => *b >= beta,
-                    3 => {
-                        // b(x) >= 1.0 or [b(x) >= alpha and Σ_{y: neighbor of x} d(y) < theta]
-                        *b >= 1.0
-                            || (*b >= alpha
-                                && d_new[[(i + 1) % n, j]]
-                                    + d_new[[(i + n - 1) % n, j]]
-                                    + d_new[[i, (j + 1) % n]]
-                                    + d_new[[i, (j + n - 1) % n]]
-                                    + d_new[[(i + n - 1) % n, (j + 1) % n]]
-                                    + d_new[[(i + 1) % n, (j + n - 1) % n]]
-                                    < theta)
-                    }
-                    _ => true,
-                };
-
-                if *a {
-                    *c += *b;
-                    *b = 0.0;
-                }
-            });
-
-        // (iv) Melting
-        Zip::indexed(&mut b_new)
-            .and(&mut c_new)
-            .and(&mut d_new)
-            .for_each(|(i, j), b, c, d| {
-                let boundary = !a_new[[i, j]]
-                    && (a_new[[(i + 1) % n, j]]
-                        || a_new[[(i + n - 1) % n, j]]
-                        || a_new[[i, (j + 1) % n]]
-                        || a_new[[i, (j + n - 1) % n]]
-                        || a_new[[(i + n - 1) % n, (j + 1) % n]]
-                        || a_new[[(i + 1) % n, (j + n - 1) % n]]);
-                if boundary {
-                    let mu_b = mu * *b;
-                    let gamma_c = gamma * *c;
-                    *b -= mu_b;
-                    *c -= gamma_c;
-                    *d += mu_b + gamma_c;
-                }
-            });
-
-        // (v) Noise
-        if sigma.abs() > 0.0 {
-            let noise = Array2::<bool>::random(d_new.raw_dim(), Standard);
-            Zip::from(&mut d_new).and(&noise).for_each(|d, &noise| {
-                if noise {
-                    *d *= 1.0 + sigma;
-                } else {
-                    *d *= 1.0 - sigma;
-                }
-            });
-        }
-
-        self.a = a_new;
-        self.b = b_new;
-        self.c = c_new;
-        self.d = d_new;
-    }
+/// What the state was last built from at step 0: the grid size, ρ and the seed.
+type InitKey = (usize, f32, u64);
+
+/// The state of the simulation and what was last written to the parameter log.
+#[derive(Resource, Default)]
+struct SimulationState {
+    /// created on the first step after a reset
+    state: Option<State>,
+    /// what `state` was built from, cleared by a reset to build it again
+    initialized_with: Option<InitKey>,
+    logged_config: Option<SimulationConfigInner>,
+    logged_radius: f32,
 }
 
-fn event_listener(field: Res<Field>, mut reset_events: EventReader<ControlEvent>) {
+fn event_listener(
+    field: Res<Field>,
+    log: Res<SimulationConfigLogInner>,
+    mut save_status: ResMut<SaveStatus>,
+    mut simulation: ResMut<SimulationState>,
+    config: Res<SimulationConfigInner>,
+    mut reset_events: EventReader<ControlEvent>,
+) {
     for event in reset_events.read() {
         match event {
             ControlEvent::Reset => {
                 field.0.write().step = 0;
+                // 塗った状態も、同じパラメータのまま作り直す
+                simulation.initialized_with = None;
+            }
+            ControlEvent::SingleStep => {
+                field.0.write().pending_steps += 1;
             }
-            ControlEvent::Save(_) => {
-                tracing::warn!("Saving is not supported on this platform");
+            // STLはmainでダウンロードされる
+            ControlEvent::Save(now) => {
+                download_log(&log, file_name(*now, "csv"), &mut save_status);
+            }
+            ControlEvent::SaveAs(path) => {
+                let name = path.with_extension("csv");
+                let name = name.file_name().unwrap_or_default().to_string_lossy();
+                download_log(&log, name.into_owned(), &mut save_status);
+            }
+            ControlEvent::SaveState(path)
+            | ControlEvent::LoadState(path)
+            | ControlEvent::SaveNpz(path) => {
+                tracing::warn!("{} cannot be opened in the browser", path.display());
+            }
+            ControlEvent::Paint { i, j, brush } => {
+                let Some(state) = &mut simulation.state else {
+                    continue;
+                };
+                state.paint((*i, *j), *brush, config.rho);
+                let mut field = field.0.write();
+                field.set_cells(state.cells());
+                field.radius = crystal_radius(&field.cells);
             }
         }
     }
 }
 
+fn download_log(log: &SimulationConfigLogInner, name: String, save_status: &mut SaveStatus) {
+    let result = log
+        .to_csv_bytes()
+        .and_then(|bytes| download::download(&name, &bytes));
+    save_status.report("CSV", result.map(|()| name.into()));
+}
+
 fn update_simulation(
     field: ResMut<Field>,
-    mut state: ResMut<State>,
+    mut simulation: ResMut<SimulationState>,
     config: Res<SimulationConfigInner>,
     mut log: ResMut<SimulationConfigLogInner>,
 ) {
-    let n = field.0.read().cells.shape()[0];
-    if field.0.read().step == 0 {
+    let simulation = &mut *simulation;
+    // ステップ0で止まっている間に毎回作り直すと、塗った結晶が消えてしまう
+    let key = (field.0.read().n, config.rho, config.seed);
+    if field.0.read().step == 0 && simulation.initialized_with != Some(key) {
         log.clear();
-        *state = State::new(n, config.rho);
-        field.0.write().cells =
-            Zip::from(&state.a)
-                .and(&state.c)
-                .map_collect(|&a, &c| if a { c } else { 0.0 });
+        let state = State::new(key.0, config.rho, config.seed(), &SeedPattern::default());
+        let mut field = field.0.write();
+        field.set_cells(state.cells());
+        field.radius = 0.0;
+        simulation.state = Some(state);
+        simulation.initialized_with = Some(key);
     }
-    if !field.0.read().is_running {
+    let Some(state) = &mut simulation.state else {
+        return;
+    };
+    if !field.0.read().is_running && field.0.read().pending_steps == 0 {
+        field.0.write().run_clock.pause();
         return;
     }
-    // if old_config != config || field.0.read().step == 0 {
-    //     tracing::info!("Step: {}, {config:?}", field.read().step);
-    //     log.write()
-    //         .push(SimulationConfigLogRecord::new(field.read().step, &config));
-    //     old_config = config;
-    // }
     let mut field = field.0.write();
-    if field.step % 100 == 0 {
-        let total_mass = state.b.sum() + state.c.sum() + state.d.sum();
-        tracing::debug!("step: {}, total_mass: {total_mass}", field.step);
+    let config = *config;
+    // ネイティブの Simulation::step と同じく、変更時と半径が伸びたときに記録する
+    if simulation.logged_config != Some(config) || field.step == 0 {
+        tracing::info!("Step: {}, {config:?}", field.step);
+    }
+    if simulation.logged_config != Some(config)
+        || field.step == 0
+        || field.radius > simulation.logged_radius
+    {
+        log.push(SimulationConfigLogRecord {
+            symmetry_error: symmetry::symmetry_error(&field.cells),
+            frozen_cells: field.frozen_cells,
+            ..SimulationConfigLogRecord::new(field.step, &config, field.radius)
+        });
+        simulation.logged_config = Some(config);
+        simulation.logged_radius = field.radius;
+    }
+    field.pending_steps = field.pending_steps.saturating_sub(1);
+    field.track_run();
+    if field.step.is_multiple_of(100) {
+        tracing::debug!("step: {}, total_mass: {}", field.step, state.total_mass());
     }
     field.step += 1;
-    state.update(*config);
-    field.cells = Zip::from(&state.a)
-        .and(&state.c)
-        .map_collect(|&a, &c| if a { c } else { 0.0 });
+    state.update(config);
+    field.set_cells(state.cells());
+    field.radius = crystal_radius(&field.cells);
+    field.record_mass(Some(state.total_mass()));
+}
+
+/// Follows the speed setting by changing the fixed timestep.
+fn update_timestep(config: Res<SimulationConfigInner>, mut time: ResMut<Time<Fixed>>) {
+    let hz = config.steps_per_second.clamp(1.0, MAX_STEPS_PER_SECOND) as f64;
+    if (time.timestep().as_secs_f64() * hz - 1.0).abs() > 1e-6 {
+        time.set_timestep_hz(hz);
+    }
 }
 
 fn configure_ui(mut contexts: EguiContexts, mut config: ResMut<SimulationConfigInner>) {
     egui::Window::new("Gravner-Griffeath's Snowflake").show(contexts.ctx_mut(), |ui| {
         ui.vertical(|ui| {
-            ui.add(egui::Slider::new(&mut config.rho, 0.0..=1.0).text("ρ: vapor density"));
-            ui.add(egui::Slider::new(&mut config.beta, 1.0..=4.0).text("β: anisotropy"));
-            ui.add(
-                egui::Slider::new(&mut config.alpha, 0.0..=1.0)
-                    .text("α: attachment threshold for b"),
-            );
-            ui.add(
-                egui::Slider::new(&mut config.theta, 0.0..=0.5)
-                    .text("θ: attachment threshold for d")
-                    .logarithmic(true),
-            );
-            ui.add(
-                egui::Slider::new(&mut config.kappa, 0.0..=1.0)
-                    .text("κ: freezing rate")
-                    .logarithmic(true),
-            );
-            ui.add(egui::Slider::new(&mut config.mu, 0.0..=0.3).text("μ: melting rate"));
-            ui.add(
-                egui::Slider::new(&mut config.gamma, 0.0..=0.01)
-                    .text("γ: sublimation rate")
-                    .logarithmic(true),
-            );
+            parameter_sliders(ui, &mut config);
+            ui.horizontal(|ui| {
+                for (k, weight) in config.direction_weights.iter_mut().enumerate() {
+                    ui.add(
+                        egui::DragValue::new(weight)
+                            .range(0.0..=10.0)
+                            .speed(0.01)
+                            .prefix(format!("{}°: ", k * 60)),
+                    );
+                }
+                ui.label("direction weights");
+            });
+            ui.horizontal(|ui| {
+                let mut seed = config.seed.to_string();
+                if ui.text_edit_singleline(&mut seed).changed() {
+                    if seed.is_empty() {
+                        config.seed = 0;
+                    } else if let Ok(seed) = seed.parse() {
+                        config.seed = seed;
+                    }
+                }
+                ui.label("seed (0: random)");
+            });
             ui.add(
-                egui::Slider::new(&mut config.sigma, 0.0..=1.0)
-                    .text("σ: noise")
+                egui::Slider::new(&mut config.steps_per_second, 1.0..=MAX_STEPS_PER_SECOND)
+                    .text("steps per second")
                     .logarithmic(true),
             );
         });
@@ -0,0 +1,157 @@
+//! Crystal mass over the steps of a run, shown as the growth curve.
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use chrono::Local;
+use ndarray::Array2;
+
+use crate::{file_dialog, Field, OutputDirectory, SaveStatus, TIMESTAMP_FORMAT};
+
+/// Samples kept at most; beyond this every other sample is dropped and the interval doubled.
+const MAX_SAMPLES: usize = 10_000;
+
+/// Mass of the crystal sampled every `interval` steps since the last reset.
+#[derive(Clone, Debug)]
+pub struct MassHistory {
+    pub interval: u64,
+    samples: Vec<MassSample>,
+}
+
+impl Default for MassHistory {
+    fn default() -> Self {
+        Self {
+            interval: 10,
+            samples: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+pub struct MassSample {
+    pub step: u64,
+    /// sum of the mass of the frozen cells
+    pub crystal_mass: f32,
+    /// mass of the whole model, if it is meant to be conserved
+    pub total_mass: Option<f32>,
+}
+
+impl MassHistory {
+    /// Samples the crystal mass of `cells` if `step` is due.
+    pub fn record(&mut self, step: u64, cells: &Array2<f32>, total_mass: Option<f32>) {
+        if !step.is_multiple_of(self.interval.max(1)) {
+            return;
+        }
+        self.samples.push(MassSample {
+            step,
+            crystal_mass: cells.iter().filter(|&&c| c > 0.0).sum(),
+            total_mass,
+        });
+        if self.samples.len() > MAX_SAMPLES {
+            // 長い実行でもメモリと描画の量を一定に保つ
+            self.interval *= 2;
+            let interval = self.interval;
+            self.samples
+                .retain(|sample| sample.step.is_multiple_of(interval));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    pub fn samples(&self) -> &[MassSample] {
+        &self.samples
+    }
+
+    /// Encodes the samples as the contents of a CSV file with a row per sample.
+    pub fn to_csv_bytes(&self) -> std::io::Result<Vec<u8>> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for sample in &self.samples {
+            writer.serialize(sample)?;
+        }
+        writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+pub fn configure_ui(
+    mut contexts: EguiContexts,
+    field: Res<Field>,
+    output_directory: Res<OutputDirectory>,
+    mut save_status: ResMut<SaveStatus>,
+    // ファイルダイアログはメインスレッドで開く
+    _main_thread: Option<NonSend<bevy::core::NonSendMarker>>,
+) {
+    egui::Window::new("Growth")
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                let mut interval = field.0.read().mass_history.interval;
+                ui.add(
+                    egui::DragValue::new(&mut interval)
+                        .range(1..=10000)
+                        .prefix("every ")
+                        .suffix(" steps"),
+                )
+                .on_hover_text("How often the mass is sampled");
+                if interval != field.0.read().mass_history.interval {
+                    field.0.write().mass_history.interval = interval;
+                }
+                if ui.button("Export CSV").clicked() {
+                    let name = format!(
+                        "snowflake-mass-{}.csv",
+                        Local::now().format(TIMESTAMP_FORMAT)
+                    );
+                    // 書き出す間シミュレーションを止めないよう複製する
+                    let history = field.0.read().mass_history.clone();
+                    if let Some(result) =
+                        file_dialog::save_bytes_as(&output_directory, &name, || {
+                            history.to_csv_bytes()
+                        })
+                    {
+                        save_status.report("mass CSV", result);
+                    }
+                }
+            });
+            let points: Vec<[f64; 2]> = field
+                .0
+                .read()
+                .mass_history
+                .samples()
+                .iter()
+                .map(|sample| [sample.step as f64, sample.crystal_mass as f64])
+                .collect();
+            egui_plot::Plot::new("crystal mass")
+                .height(160.0)
+                .x_axis_label("step")
+                .y_axis_label("crystal mass")
+                .allow_scroll(false)
+                .show(ui, |plot_ui| {
+                    plot_ui.line(egui_plot::Line::new(points));
+                });
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_thins_out_long_runs() {
+        let cells = ndarray::array![[0.0, 1.0], [2.0, 0.5]];
+        let mut history = MassHistory {
+            interval: 1,
+            ..Default::default()
+        };
+        for step in 1..=(MAX_SAMPLES as u64 + 1) {
+            history.record(step, &cells, None);
+        }
+        assert_eq!(history.interval, 2);
+        assert_eq!(history.samples().len(), MAX_SAMPLES / 2);
+        assert_eq!(history.samples()[0].crystal_mass, 3.5);
+        history.clear();
+        history.record(2, &cells, Some(4.0));
+        assert_eq!(history.samples().len(), 1);
+        let csv = String::from_utf8(history.to_csv_bytes().unwrap()).unwrap();
+        assert_eq!(csv, "step,crystal_mass,total_mass\n2,3.5,4.0\n");
+    }
+}
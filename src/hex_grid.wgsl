@@ -0,0 +1,76 @@
+// 2D表示の六角格子。visualization.rs の HexGridMaterial から使う。
+// 各画素を含む六角形 (正方格子では正方形) のセルを求め、その色をセルごとに1テクセルのテクスチャから取る。
+
+#import bevy_sprite::mesh2d_vertex_output::VertexOutput
+
+struct HexGrid {
+    n: u32,
+    // セルの間隔
+    scale: f32,
+    // セルの枠の太さ。0 なら描かない
+    border: f32,
+    // 0 なら六角格子、それ以外は正方格子
+    lattice: u32,
+}
+
+const BORDER_COLOR: vec4<f32> = vec4<f32>(0.0, 0.0, 0.0, 0.8);
+
+@group(2) @binding(0) var<uniform> grid: HexGrid;
+// (i, j) のテクセルがセル (i, j) の色。透明なセルは描かない
+@group(2) @binding(1) var cells: texture_2d<f32>;
+
+@fragment
+fn fragment(mesh: VertexOutput) -> @location(0) vec4<f32> {
+    let n = f32(grid.n);
+    let p = mesh.world_position.xy / grid.scale;
+    var ri: f32;
+    var rj: f32;
+    // セルの辺までの距離。内接円の半径は 0.5
+    var edge: f32;
+    if grid.lattice != 0u {
+        // visualization.rs の cell_position の逆。正方形は丸めるだけで求まる
+        let i = p.x + floor(n / 2.0);
+        let j = p.y + floor(n / 2.0);
+        ri = round(i);
+        rj = round(j);
+        edge = 0.5 - max(abs(i - ri), abs(j - rj));
+    } else {
+        // visualization.rs の cell_position の逆
+        let j = p.y / (sqrt(3.0) / 2.0) + floor(n / 2.0);
+        let i = p.x + n * 0.75 - j / 2.0;
+
+        // 格子の点 i + j / 2 の周りの六角形は、キューブ座標 (i, j, -i - j) の丸めで求まる
+        ri = round(i);
+        rj = round(j);
+        let rk = round(-i - j);
+        let di = abs(ri - i);
+        let dj = abs(rj - j);
+        let dk = abs(rk + i + j);
+        if di > dj && di > dk {
+            ri = -rj - rk;
+        } else if dj > dk {
+            rj = -ri - rk;
+        }
+
+        let d = vec2<f32>(i - ri + (j - rj) / 2.0, (j - rj) * sqrt(3.0) / 2.0);
+        let s = sqrt(3.0) / 2.0;
+        edge = 0.5 - max(abs(d.x), max(abs(0.5 * d.x + s * d.y), abs(0.5 * d.x - s * d.y)));
+    }
+    // 画素1つ分の幅でぼかす。微分は discard より前に取る
+    let aa = fwidth(edge);
+
+    if ri < 0.0 || rj < 0.0 || ri >= n || rj >= n {
+        discard;
+    }
+    let color = textureLoad(cells, vec2<i32>(i32(ri), i32(rj)), 0);
+    if color.a == 0.0 {
+        discard;
+    }
+    if grid.border <= 0.0 {
+        return color;
+    }
+    // 隣のセルと半分ずつ描いて、合わせて border の太さにする
+    let half = grid.border / 2.0;
+    let t = 1.0 - smoothstep(half - aa, half + aa, edge);
+    return mix(color, vec4<f32>(BORDER_COLOR.rgb, max(color.a, BORDER_COLOR.a)), t);
+}
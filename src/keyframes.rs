@@ -0,0 +1,148 @@
+//! Keyframes that drive the Gravner-Griffeath parameters over the steps of a run, like a crystal
+//! falling through layers of air of different temperature and humidity (the Nakaya diagram).
+
+use bevy_egui::egui;
+
+use crate::gravner_griffeath::{Parameter, SimulationConfigInner};
+
+/// The parameters ρ to σ at a step.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keyframe {
+    pub step: u64,
+    /// values of [`Parameter::ALL`]
+    pub values: [f32; 8],
+}
+
+impl Keyframe {
+    /// The current parameters of `config` at `step`.
+    pub fn of(step: u64, config: &SimulationConfigInner) -> Self {
+        Self {
+            step,
+            values: Parameter::ALL.map(|parameter| parameter.get(config)),
+        }
+    }
+}
+
+/// Piecewise-linear schedule of the parameters over the steps.
+///
+/// Before the first keyframe the parameters hold its values, and after the last one its values.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParameterKeyframes {
+    pub is_enabled: bool,
+    /// sorted by step
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl ParameterKeyframes {
+    /// Parameters interpolated at `step`, or `None` without keyframes.
+    pub fn values_at(&self, step: u64) -> Option<[f32; 8]> {
+        let next = self
+            .keyframes
+            .partition_point(|keyframe| keyframe.step <= step);
+        let previous = next.checked_sub(1).map(|k| &self.keyframes[k]);
+        match (previous, self.keyframes.get(next)) {
+            (None, None) => None,
+            (Some(keyframe), None) | (None, Some(keyframe)) => Some(keyframe.values),
+            (Some(from), Some(to)) => {
+                // 同じステップの keyframe が並んでいても to.step > from.step になる
+                let t = (step - from.step) as f32 / (to.step - from.step) as f32;
+                Some(std::array::from_fn(|k| {
+                    from.values[k] + t * (to.values[k] - from.values[k])
+                }))
+            }
+        }
+    }
+
+    /// Overwrites the parameters of `config` with their values at `step`, if enabled.
+    pub fn apply_to(&self, step: u64, config: &mut SimulationConfigInner) {
+        if !self.is_enabled {
+            return;
+        }
+        if let Some(values) = self.values_at(step) {
+            for (parameter, value) in Parameter::ALL.into_iter().zip(values) {
+                parameter.set(config, value);
+            }
+        }
+    }
+
+    fn sort(&mut self) {
+        self.keyframes.sort_by_key(|keyframe| keyframe.step);
+    }
+}
+
+/// Table of the keyframes, with a button to add the parameters of `config` at `step`.
+pub fn keyframes_ui(
+    ui: &mut egui::Ui,
+    keyframes: &mut ParameterKeyframes,
+    config: &SimulationConfigInner,
+    step: u64,
+) {
+    ui.checkbox(
+        &mut keyframes.is_enabled,
+        "drive the parameters by the keyframes",
+    )
+    .on_hover_text(
+        "While enabled, the worker overwrites ρ to σ every step with the values interpolated \
+             between the keyframes; the sliders follow them.",
+    );
+    let mut removed = None;
+    egui::Grid::new("keyframes").striped(true).show(ui, |ui| {
+        ui.label("step");
+        for parameter in Parameter::ALL {
+            ui.label(parameter.symbol());
+        }
+        ui.end_row();
+        for (k, keyframe) in keyframes.keyframes.iter_mut().enumerate() {
+            ui.add(egui::DragValue::new(&mut keyframe.step).speed(10.0));
+            for value in &mut keyframe.values {
+                ui.add(
+                    egui::DragValue::new(value)
+                        .range(0.0..=f32::INFINITY)
+                        .speed(0.001)
+                        .max_decimals(6),
+                );
+            }
+            if ui.button("✖").clicked() {
+                removed = Some(k);
+            }
+            ui.end_row();
+        }
+    });
+    if let Some(k) = removed {
+        keyframes.keyframes.remove(k);
+    }
+    if ui
+        .button(format!("Add current parameters at step {step}"))
+        .clicked()
+    {
+        keyframes.keyframes.push(Keyframe::of(step, config));
+    }
+    // 編集中に行が入れ替わらないよう、ステップを変えている間は並べ直さない
+    if !ui.ctx().is_using_pointer() {
+        keyframes.sort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_interpolated_between_keyframes() {
+        let config = SimulationConfigInner::default();
+        let mut keyframes = ParameterKeyframes {
+            is_enabled: true,
+            keyframes: vec![Keyframe::of(100, &config), Keyframe::of(300, &config)],
+        };
+        keyframes.keyframes[1].values[1] = config.beta + 2.0;
+        assert_eq!(keyframes.values_at(0).unwrap()[1], config.beta);
+        assert!((keyframes.values_at(200).unwrap()[1] - (config.beta + 1.0)).abs() < 1e-6);
+        assert_eq!(keyframes.values_at(1000).unwrap()[1], config.beta + 2.0);
+
+        let mut applied = config;
+        keyframes.apply_to(250, &mut applied);
+        assert!((applied.beta - (config.beta + 1.5)).abs() < 1e-6);
+        assert_eq!(applied.rho, config.rho);
+        assert_eq!(ParameterKeyframes::default().values_at(0), None);
+    }
+}
@@ -0,0 +1,1814 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bevy::{
+    diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin},
+    log::LogPlugin,
+    prelude::*,
+    utils::Instant,
+    window::PrimaryWindow,
+};
+use bevy_egui::{egui, EguiContexts, EguiPlugin};
+use chrono::DateTime;
+use clap::Parser as _;
+use ndarray::Array2;
+use parking_lot::RwLock;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod cells_csv;
+mod compare;
+mod config_file;
+mod contour;
+#[cfg(target_arch = "wasm32")]
+mod download;
+mod file_dialog;
+mod gallery;
+#[cfg(not(target_arch = "wasm32"))]
+mod gif;
+mod gravner_griffeath;
+#[cfg(feature = "gpu")]
+mod gravner_griffeath_gpu;
+#[cfg(target_arch = "wasm32")]
+mod gravner_griffeath_wasm;
+mod growth;
+#[cfg(not(target_arch = "wasm32"))]
+mod keyframes;
+mod montage;
+mod npy;
+#[cfg(not(target_arch = "wasm32"))]
+mod obj;
+mod pause_rules;
+mod png;
+#[cfg(not(target_arch = "wasm32"))]
+mod preset;
+#[cfg(not(target_arch = "wasm32"))]
+mod reiter;
+mod spectrum;
+mod stl;
+#[cfg(not(target_arch = "wasm32"))]
+mod svg;
+mod sweep;
+mod symmetry;
+mod view_state;
+mod visualization;
+
+/// What the benchmarks in `benches/` call, without the app around it.
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::{
+        gravner_griffeath::{Lattice, SeedPattern, SimulationConfigInner, State},
+        stl::{cells_to_triangles, Relief},
+    };
+}
+
+/// Runs the app with the command line arguments.
+pub fn run() {
+    let args = Args::parse();
+    // コマンドラインの値はファイルの値より優先する
+    let (n, mut config) = match &args.config {
+        Some(path) => match config_file::ConfigFile::load(path) {
+            Ok(file) => (file.n, file.parameters),
+            Err(e) => {
+                eprintln!("failed to load {}: {e}", path.display());
+                std::process::exit(2);
+            }
+        },
+        None => (DEFAULT_GRID_SIZE, default()),
+    };
+    args.parameters.apply_to(&mut config);
+    if args.headless {
+        if args.model != Model::GravnerGriffeath {
+            eprintln!("headless mode supports only the gravner-griffeath model");
+            std::process::exit(2);
+        }
+        App::new()
+            .insert_resource(Field(Arc::new(RwLock::new(FieldInner::new(n)))))
+            .add_plugins((MinimalPlugins, LogPlugin::default()))
+            .add_plugins(gravner_griffeath::GravnerGrifeeathHeadlessPlugin {
+                steps: args.steps,
+                config,
+                sweep: args.sweep,
+            })
+            .run();
+        return;
+    }
+
+    let mut app = App::new();
+    app.insert_resource(Field(Arc::new(RwLock::new(FieldInner {
+        model: args.model,
+        ..FieldInner::new(n)
+    }))))
+    .init_resource::<StatePath>()
+    .init_resource::<ExportSettings>()
+    .init_resource::<gallery::SnapshotGallery>()
+    .init_resource::<symmetry::SymmetryAnalysis>()
+    .init_resource::<spectrum::SpectrumAnalysis>()
+    .init_resource::<compare::Comparison>()
+    .init_resource::<StepRate>()
+    .init_resource::<BackgroundSaves>()
+    .init_resource::<SaveStatus>()
+    .init_resource::<OutputDirectory>()
+    .insert_resource(view_state::ViewState::load())
+    .add_event::<ControlEvent>()
+    .add_plugins((DefaultPlugins, EguiPlugin, FrameTimeDiagnosticsPlugin))
+    .add_plugins(SimulatorPlugins { config })
+    .add_plugins(visualization::VisualizationPlugin)
+    .add_systems(
+        Startup,
+        (
+            start_simulation,
+            set_window_title,
+            view_state::restore_window,
+        ),
+    )
+    .add_systems(
+        Update,
+        (
+            configure_ui,
+            measure_step_rate,
+            finish_background_saves,
+            gallery::capture_snapshots,
+            gallery::configure_ui,
+            symmetry::configure_ui,
+            spectrum::configure_ui,
+            growth::configure_ui,
+            compare::configure_ui,
+            auto_save_when_stopped,
+            save_stl,
+            view_state::track_window,
+        ),
+    )
+    .add_systems(Last, (view_state::save_on_exit, wait_for_background_saves));
+    // 録画した GIF はファイルに書くしかないので、ブラウザでは録画しない
+    #[cfg(not(target_arch = "wasm32"))]
+    app.init_resource::<gif::GifRecorder>()
+        .add_systems(Update, gif::record_frames);
+    app.run();
+}
+
+/// The models' simulators.
+///
+/// They run on worker threads natively; the browser has no threads, so there the model is
+/// stepped in `FixedUpdate` instead.
+struct SimulatorPlugins {
+    config: gravner_griffeath::SimulationConfigInner,
+}
+
+impl Plugin for SimulatorPlugins {
+    fn build(&self, app: &mut App) {
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_plugins((
+            gravner_griffeath::GravnerGrifeeathSimulatorPlugin {
+                config: self.config,
+            },
+            reiter::ReiterSimulatorPlugin,
+        ));
+        #[cfg(target_arch = "wasm32")]
+        app.add_plugins(
+            gravner_griffeath_wasm::GravnerGrifeeathSimulatorWasmPlugin {
+                config: self.config,
+            },
+        );
+    }
+}
+
+/// Snow crystal growth models.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Model {
+    #[default]
+    GravnerGriffeath,
+    Reiter,
+}
+
+impl Model {
+    /// The models this build can run; the Reiter model needs a worker thread.
+    const AVAILABLE: &'static [Model] = if cfg!(target_arch = "wasm32") {
+        &[Model::GravnerGriffeath]
+    } else {
+        &[Model::GravnerGriffeath, Model::Reiter]
+    };
+
+    fn label(&self) -> &'static str {
+        match self {
+            Model::GravnerGriffeath => "Gravner-Griffeath",
+            Model::Reiter => "Reiter",
+        }
+    }
+}
+
+/// Command line arguments.
+#[derive(clap::Parser)]
+#[command(about = "Snow crystal growth simulator")]
+struct Args {
+    /// model to start with
+    #[arg(long, value_enum, default_value = "gravner-griffeath")]
+    model: Model,
+    /// run without a window and exit after `--steps` steps
+    #[arg(long)]
+    headless: bool,
+    #[arg(long, default_value_t = 1000)]
+    steps: u64,
+    /// JSON file with the grid size `n` and the parameters to start with
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// with `--headless`, run every combination of evenly spaced values of a parameter,
+    /// given as name=start:end:count; repeat for more parameters
+    #[arg(long, requires = "headless")]
+    sweep: Vec<sweep::SweepAxis>,
+    #[command(flatten)]
+    parameters: ParameterArgs,
+}
+
+/// Gravner-Griffeath parameters that replace the defaults, or those of `--config`, at startup.
+///
+/// There is an option for each of [`gravner_griffeath::Parameter::ALL`], named after it.
+#[derive(Default)]
+struct ParameterArgs {
+    /// values of the options, in the order of [`gravner_griffeath::Parameter::ALL`]
+    values: [Option<f32>; 8],
+    seed: Option<u64>,
+}
+
+impl ParameterArgs {
+    fn apply_to(&self, config: &mut gravner_griffeath::SimulationConfigInner) {
+        for (parameter, value) in gravner_griffeath::Parameter::ALL
+            .into_iter()
+            .zip(self.values)
+        {
+            if let Some(value) = value {
+                parameter.set(config, value);
+            }
+        }
+        if let Some(seed) = self.seed {
+            config.seed = seed;
+        }
+    }
+}
+
+impl clap::Args for ParameterArgs {
+    fn augment_args(command: clap::Command) -> clap::Command {
+        let command =
+            gravner_griffeath::Parameter::ALL
+                .into_iter()
+                .fold(command, |command, parameter| {
+                    let range = if parameter.max() == 1.0 {
+                        ", 0 to 1"
+                    } else {
+                        ""
+                    };
+                    command.arg(
+                        clap::Arg::new(parameter.name())
+                            .long(parameter.name())
+                            .value_name("VALUE")
+                            .value_parser(move |value: &str| parameter_value(parameter, value))
+                            .help(format!(
+                                "{}: {}{range}",
+                                parameter.symbol(),
+                                parameter.description()
+                            )),
+                    )
+                });
+        command.arg(
+            clap::Arg::new("seed")
+                .long("seed")
+                .value_name("SEED")
+                .value_parser(clap::value_parser!(u64))
+                .help("random seed for the noise (0: random)"),
+        )
+    }
+
+    fn augment_args_for_update(command: clap::Command) -> clap::Command {
+        Self::augment_args(command)
+    }
+}
+
+impl clap::FromArgMatches for ParameterArgs {
+    fn from_arg_matches(matches: &clap::ArgMatches) -> Result<Self, clap::Error> {
+        let mut args = Self::default();
+        args.update_from_arg_matches(matches)?;
+        Ok(args)
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &clap::ArgMatches) -> Result<(), clap::Error> {
+        for (parameter, value) in gravner_griffeath::Parameter::ALL
+            .into_iter()
+            .zip(&mut self.values)
+        {
+            if let Some(&given) = matches.get_one::<f32>(parameter.name()) {
+                *value = Some(given);
+            }
+        }
+        if let Some(&seed) = matches.get_one::<u64>("seed") {
+            self.seed = Some(seed);
+        }
+        Ok(())
+    }
+}
+
+/// Parses the value of the option of `parameter`, from 0 to [`gravner_griffeath::Parameter::max`].
+fn parameter_value(parameter: gravner_griffeath::Parameter, value: &str) -> Result<f32, String> {
+    let value: f32 = value.parse().map_err(|e| format!("{e}"))?;
+    // NaN もここで弾く
+    if !(value >= 0.0 && value.is_finite()) {
+        return Err(format!("{value} is not a finite non-negative number"));
+    }
+    if value <= parameter.max() {
+        Ok(value)
+    } else {
+        Err(format!("{value} is not between 0 and {}", parameter.max()))
+    }
+}
+
+fn start_simulation(field: Res<Field>) {
+    let mut field = field.0.write();
+    field.is_running = true;
+}
+
+#[derive(Event)]
+enum ControlEvent {
+    Reset,
+    SingleStep,
+    /// save the STL and the parameter log, both named with this timestamp
+    Save(DateTime<chrono::Local>),
+    /// save the STL to this path and the parameter log next to it with the `csv` extension
+    SaveAs(PathBuf),
+    SaveState(PathBuf),
+    LoadState(PathBuf),
+    /// save all fields of the model as a NumPy archive
+    SaveNpz(PathBuf),
+    /// edit the cells around `(i, j)` with the painting tool of the 2D view
+    Paint {
+        i: usize,
+        j: usize,
+        brush: Brush,
+    },
+}
+
+/// One stroke of the painting tool of the 2D view.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Brush {
+    pub kind: PaintKind,
+    /// lattice distance from the cursor within which the cells are painted
+    pub radius: usize,
+    pub mirror: Mirror,
+}
+
+/// Symmetry the painting tool keeps by repeating each stroke around the center of the grid.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mirror {
+    #[default]
+    Off,
+    /// the six rotations by 60°
+    Rotations,
+    /// the six rotations and their reflections
+    Reflections,
+}
+
+impl Mirror {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Mirror::Off => "off",
+            Mirror::Rotations => "6-fold",
+            Mirror::Reflections => "12-fold",
+        }
+    }
+}
+
+/// What the painting tool of the 2D view does to a cell.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaintKind {
+    /// add this much vapor
+    Vapor(f32),
+    /// freeze the cell as if it had attached
+    Freeze,
+    /// clear the cell back to the background vapor of the model
+    Erase,
+}
+
+impl PaintKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            PaintKind::Vapor(_) => "vapor",
+            PaintKind::Freeze => "freeze",
+            PaintKind::Erase => "erase",
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct Field(pub Arc<RwLock<FieldInner>>);
+
+/// Grid size `n` when none is given.
+pub const DEFAULT_GRID_SIZE: usize = 1000;
+
+/// Step limit offered when it is first enabled.
+const DEFAULT_MAX_STEP: u64 = 10000;
+
+/// How often a paused worker thread checks whether to resume.
+pub const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Options for the exported files.
+#[derive(Resource)]
+struct ExportSettings {
+    /// fill the SVG outline instead of only stroking it
+    #[cfg(not(target_arch = "wasm32"))]
+    svg_fill: bool,
+    stl_format: stl::StlFormat,
+    stl_geometry: stl::StlGeometry,
+    /// export only a 60° wedge of the STL and OBJ
+    wedge: bool,
+    /// horizontal size of a cell spacing in the STL and OBJ
+    xy_scale: f32,
+    /// height of the STL and OBJ per unit of crystal mass
+    z_scale: f32,
+    /// save the STL and the log when the step limit or the target radius is reached
+    save_when_stopped: bool,
+    height_mapping: stl::HeightMapping,
+    /// quantize the STL and OBJ heights into `terrace_levels` steps
+    terraces: bool,
+    terrace_levels: u32,
+    /// rounds of smoothing of the STL and OBJ heights
+    smoothing: u32,
+    /// widen the arms of the STL and OBJ thinner than `min_feature` cell spacings
+    thicken: bool,
+    min_feature: f32,
+    #[cfg(not(target_arch = "wasm32"))]
+    cells_csv_format: cells_csv::CellsCsvFormat,
+    /// pixels per cell spacing of the heightmap PNG
+    #[cfg(not(target_arch = "wasm32"))]
+    heightmap_scale: f32,
+}
+
+impl Default for ExportSettings {
+    fn default() -> Self {
+        Self {
+            #[cfg(not(target_arch = "wasm32"))]
+            svg_fill: false,
+            stl_format: stl::StlFormat::default(),
+            stl_geometry: stl::StlGeometry::default(),
+            wedge: false,
+            xy_scale: stl::XY_SCALE,
+            z_scale: stl::Z_SCALE,
+            save_when_stopped: false,
+            height_mapping: stl::HeightMapping::default(),
+            terraces: false,
+            terrace_levels: 8,
+            smoothing: 0,
+            thicken: false,
+            min_feature: 3.0,
+            #[cfg(not(target_arch = "wasm32"))]
+            cells_csv_format: cells_csv::CellsCsvFormat::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            heightmap_scale: 2.0,
+        }
+    }
+}
+
+impl ExportSettings {
+    /// Height mapping, terraces, smoothing and thickening of the exported relief.
+    fn relief(&self) -> stl::Relief {
+        stl::Relief {
+            mapping: self.height_mapping,
+            terrace_levels: self.terraces.then_some(self.terrace_levels),
+            smoothing: self.smoothing,
+            min_feature: self.thicken.then_some(self.min_feature),
+        }
+    }
+
+    /// Options of the STL of cells on `lattice`.
+    fn stl_options(&self, lattice: gravner_griffeath::Lattice) -> stl::StlOptions {
+        stl::StlOptions {
+            format: self.stl_format,
+            lattice,
+            xy_scale: self.xy_scale,
+            z_scale: self.z_scale,
+            relief: self.relief(),
+            geometry: self.stl_geometry,
+            wedge: self.wedge,
+        }
+    }
+}
+
+/// Saves running on background threads, so that writing large grids does not freeze the UI.
+///
+/// The browser has no threads, so there a save runs to the end when it is started.
+#[derive(Resource, Default)]
+pub struct BackgroundSaves(Vec<BackgroundSave>);
+
+struct BackgroundSave {
+    /// file type in the log
+    kind: &'static str,
+    handle: SaveHandle,
+    progress: Option<SaveProgress>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+type SaveHandle = std::thread::JoinHandle<std::io::Result<PathBuf>>;
+
+/// The result of a save that has already run.
+#[cfg(target_arch = "wasm32")]
+type SaveHandle = std::io::Result<PathBuf>;
+
+#[cfg(not(target_arch = "wasm32"))]
+fn start_save(save: impl FnOnce() -> std::io::Result<PathBuf> + Send + 'static) -> SaveHandle {
+    std::thread::spawn(save)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn start_save(save: impl FnOnce() -> std::io::Result<PathBuf> + Send + 'static) -> SaveHandle {
+    save()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn is_finished(handle: &SaveHandle) -> bool {
+    handle.is_finished()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn is_finished(_: &SaveHandle) -> bool {
+    true
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn join_save(handle: SaveHandle) -> std::io::Result<PathBuf> {
+    handle
+        .join()
+        .unwrap_or_else(|_| Err(std::io::Error::other("the saving thread panicked")))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn join_save(handle: SaveHandle) -> std::io::Result<PathBuf> {
+    handle
+}
+
+/// Fraction of a background save done, from 0 to 1, shared with the saving thread.
+#[derive(Clone, Default)]
+pub struct SaveProgress(Arc<AtomicU32>);
+
+impl SaveProgress {
+    pub fn set(&self, fraction: f32) {
+        self.0.store(fraction.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+impl BackgroundSaves {
+    /// Runs `save` on a new thread; `kind` names the file type in the log.
+    pub fn spawn(
+        &mut self,
+        kind: &'static str,
+        save: impl FnOnce() -> std::io::Result<PathBuf> + Send + 'static,
+    ) {
+        self.0.push(BackgroundSave {
+            kind,
+            handle: start_save(save),
+            progress: None,
+        });
+    }
+
+    /// Like [`BackgroundSaves::spawn`], with `save` reporting how far it has come.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn spawn_with_progress(
+        &mut self,
+        kind: &'static str,
+        save: impl FnOnce(&SaveProgress) -> std::io::Result<PathBuf> + Send + 'static,
+    ) {
+        let progress = SaveProgress::default();
+        let reported = progress.clone();
+        self.0.push(BackgroundSave {
+            kind,
+            handle: start_save(move || save(&reported)),
+            progress: Some(progress),
+        });
+    }
+
+    pub fn is_busy(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    /// Progress of the least advanced save that reports it.
+    pub fn progress(&self) -> Option<f32> {
+        self.0
+            .iter()
+            .filter_map(|save| save.progress.as_ref())
+            .map(SaveProgress::get)
+            .reduce(f32::min)
+    }
+}
+
+fn finish_background_saves(mut saves: ResMut<BackgroundSaves>, mut status: ResMut<SaveStatus>) {
+    if !saves.is_busy() {
+        return;
+    }
+    let (finished, running): (Vec<_>, Vec<_>) = std::mem::take(&mut saves.0)
+        .into_iter()
+        .partition(|save| is_finished(&save.handle));
+    saves.0 = running;
+    for save in finished {
+        status.report(save.kind, join_save(save.handle));
+    }
+}
+
+/// Lets the running saves finish before the app exits.
+fn wait_for_background_saves(
+    mut exit_events: EventReader<AppExit>,
+    mut saves: ResMut<BackgroundSaves>,
+    mut status: ResMut<SaveStatus>,
+) {
+    if exit_events.read().next().is_none() {
+        return;
+    }
+    for save in saves.0.drain(..) {
+        status.report(save.kind, join_save(save.handle));
+    }
+}
+
+/// How long a save result stays in the Control window.
+const SAVE_STATUS_DURATION: Duration = Duration::from_secs(5);
+
+/// Results of the recent saves, shown in the Control window until they expire.
+#[derive(Resource, Default)]
+pub struct SaveStatus(Vec<SaveStatusLine>);
+
+struct SaveStatusLine {
+    message: String,
+    is_error: bool,
+    reported_at: Instant,
+}
+
+impl SaveStatus {
+    /// Logs the result of saving a `kind` file and shows it in the UI.
+    pub fn report(&mut self, kind: &str, result: std::io::Result<PathBuf>) {
+        let (message, is_error) = match result {
+            Ok(path) => {
+                tracing::info!("Saved {kind}: {}", path.display());
+                (format!("Saved {}", path.display()), false)
+            }
+            Err(e) => {
+                tracing::error!("Failed to save {kind}: {e}");
+                (format!("Failed to save {kind}: {e}"), true)
+            }
+        };
+        self.0.push(SaveStatusLine {
+            message,
+            is_error,
+            reported_at: Instant::now(),
+        });
+    }
+
+    fn show(&mut self, ui: &mut egui::Ui) {
+        self.0
+            .retain(|line| line.reported_at.elapsed() < SAVE_STATUS_DURATION);
+        for line in &self.0 {
+            let text = egui::RichText::new(&line.message);
+            ui.label(if line.is_error {
+                text.color(egui::Color32::RED)
+            } else {
+                text
+            });
+        }
+    }
+}
+
+/// Path of the state file to load, filled in by the last save.
+#[derive(Resource, Default)]
+struct StatePath(String);
+
+/// Format of the timestamps in the names of the saved files.
+pub const TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S";
+
+/// Name of a file saved at `now`, such as `snowflake-20240101120000.stl`.
+pub fn file_name(now: DateTime<chrono::Local>, extension: &str) -> String {
+    format!("snowflake-{}.{extension}", now.format(TIMESTAMP_FORMAT))
+}
+
+/// Directory the saved files are written to; the directory of the executable by default.
+#[derive(Resource, Clone)]
+pub struct OutputDirectory(pub PathBuf);
+
+impl Default for OutputDirectory {
+    fn default() -> Self {
+        let dir = std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(Path::to_path_buf))
+            .unwrap_or_default();
+        Self(dir)
+    }
+}
+
+impl OutputDirectory {
+    /// Path of `name` in the directory, creating the directory if needed.
+    pub fn path(&self, name: &str) -> std::io::Result<PathBuf> {
+        if !self.0.as_os_str().is_empty() {
+            std::fs::create_dir_all(&self.0)?;
+        }
+        Ok(self.0.join(name))
+    }
+
+    /// Writes the file `name` in the directory with `write` and returns its path.
+    pub fn save(
+        &self,
+        name: &str,
+        write: impl FnOnce(&Path) -> std::io::Result<()>,
+    ) -> std::io::Result<PathBuf> {
+        let path = self.path(name)?;
+        write(&path)?;
+        Ok(path)
+    }
+}
+
+pub struct FieldInner {
+    pub cells: Array2<f32>,
+    pub step: u64,
+    pub is_running: bool,
+    /// steps to run even while paused
+    pub pending_steps: u64,
+    /// grid size applied on the next reset
+    pub n: usize,
+    /// the model that drives `cells`
+    pub model: Model,
+    /// arrangement of `cells`, for drawing and exporting them
+    pub lattice: gravner_griffeath::Lattice,
+    /// crystal radius of `cells` (see [`crystal_radius`])
+    pub radius: f32,
+    /// total mass of the model, if it is meant to be conserved
+    pub mass: Option<MassBalance>,
+    /// pause once `step` reaches this
+    pub max_step: Option<u64>,
+    /// set when the run has been paused at `max_step`, until the next reset
+    pub step_limit_reached: bool,
+    /// pause once `radius` reaches `target_radius`
+    pub stop_at_radius: bool,
+    pub target_radius: f32,
+    /// set when the run has been paused at `target_radius`, until the next reset
+    pub radius_reached: bool,
+    /// pause when the crystal comes within this many cells of the grid edge
+    ///
+    /// Beyond the edge the crystal grows into itself on the periodic boundary, and into the
+    /// boundary's vapor or a wall on the others; either way it no longer grows as on an
+    /// unbounded plane.
+    pub edge_margin: usize,
+    /// set when the run has been paused at the edge, until the next reset
+    pub edge_reached: bool,
+    /// further conditions to pause the run at
+    pub pause_rules: pause_rules::PauseRules,
+    /// time spent running since the last reset, kept by the worker thread
+    pub run_clock: RunClock,
+    /// crystal mass over the steps, recorded by the worker thread
+    pub mass_history: growth::MassHistory,
+    /// cells changed since the 2D view last redrew them, kept by [`FieldInner::set_cells`]
+    pub changed: Option<visualization::CellRegion>,
+    /// smallest positive and largest mass of `cells` (see [`visualization::value_range`])
+    pub value_range: (f32, f32),
+    /// number of frozen cells, those with a positive mass, approximating the crystal's area
+    pub frozen_cells: usize,
+    /// field of the Gravner-Griffeath model to draw in the 2D view instead of `cells`
+    pub shown_layer: gravner_griffeath::FieldLayer,
+    /// values of `shown_layer`, published by the worker thread
+    pub layer: Option<gravner_griffeath::LayerValues>,
+}
+
+/// Wall-clock time a run has been stepping, excluding the time it was paused.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RunClock {
+    /// time up to the last pause
+    paused_total: Duration,
+    /// when the clock last started, if it is running
+    resumed_at: Option<Instant>,
+}
+
+impl RunClock {
+    pub fn resume(&mut self) {
+        self.resumed_at.get_or_insert_with(Instant::now);
+    }
+
+    pub fn pause(&mut self) {
+        if let Some(resumed_at) = self.resumed_at.take() {
+            self.paused_total += resumed_at.elapsed();
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.paused_total + self.resumed_at.map_or(Duration::ZERO, |t| t.elapsed())
+    }
+}
+
+/// Total mass of the simulation compared with the mass it started from.
+#[derive(Clone, Copy, Debug)]
+pub struct MassBalance {
+    pub total: f32,
+    pub baseline: f32,
+    /// whether the relative drift is beyond the tolerance
+    pub exceeded: bool,
+}
+
+impl MassBalance {
+    /// `tolerance` of the relative drift, or `None` when the mass is not expected to be kept.
+    pub fn new(total: f32, baseline: f32, tolerance: Option<f32>) -> Self {
+        let mut balance = Self {
+            total,
+            baseline,
+            exceeded: false,
+        };
+        balance.exceeded =
+            tolerance.is_some_and(|tolerance| balance.relative_drift().abs() > tolerance);
+        balance
+    }
+
+    pub fn delta(&self) -> f32 {
+        self.total - self.baseline
+    }
+
+    pub fn relative_drift(&self) -> f32 {
+        self.delta() / self.baseline
+    }
+}
+
+impl FieldInner {
+    fn new(n: usize) -> Self {
+        Self {
+            cells: Array2::<f32>::zeros((n, n)),
+            step: 0,
+            is_running: false,
+            pending_steps: 0,
+            n,
+            model: Model::default(),
+            lattice: gravner_griffeath::Lattice::default(),
+            radius: 0.0,
+            mass: None,
+            max_step: None,
+            step_limit_reached: false,
+            stop_at_radius: false,
+            target_radius: 100.0,
+            radius_reached: false,
+            edge_margin: 2,
+            edge_reached: false,
+            pause_rules: pause_rules::PauseRules::default(),
+            run_clock: RunClock::default(),
+            mass_history: growth::MassHistory::default(),
+            changed: None,
+            value_range: (0.0, 0.0),
+            frozen_cells: 0,
+            shown_layer: gravner_griffeath::FieldLayer::default(),
+            layer: None,
+        }
+    }
+
+    /// Replaces `cells`, adding the cells that differ to `changed` for the 2D view.
+    ///
+    /// The workers call this instead of assigning `cells`, so that the comparison, the range
+    /// of the values and the count of frozen cells are worked out on their threads rather than
+    /// every frame.
+    pub fn set_cells(&mut self, cells: Array2<f32>) {
+        if let Some(region) = visualization::CellRegion::of_changes(&self.cells, &cells) {
+            self.changed = Some(match self.changed {
+                Some(changed) => changed.union(region),
+                None => region,
+            });
+        }
+        (self.frozen_cells, self.value_range) = frozen_cells_and_range(&cells);
+        self.cells = cells;
+    }
+
+    /// Whether `layer` is missing or out of date for `shown_layer` at `step`.
+    pub fn layer_is_stale(&self) -> bool {
+        if self.shown_layer == gravner_griffeath::FieldLayer::FrozenMass {
+            return self.layer.is_some();
+        }
+        !matches!(
+            &self.layer,
+            Some(layer) if layer.layer == self.shown_layer && layer.step == self.step
+        )
+    }
+
+    /// Keeps `run_clock` running while steps are taken, restarting it and the mass history on
+    /// the first step of a run.
+    ///
+    /// The worker calls this before each step, and pauses the clock while the run is paused.
+    pub fn track_run(&mut self) {
+        if self.step == 0 {
+            self.run_clock = RunClock::default();
+            self.mass_history.clear();
+        }
+        self.run_clock.resume();
+    }
+
+    /// Samples the mass of `cells` into the growth curve, with the model's total mass if any.
+    pub fn record_mass(&mut self, total_mass: Option<f32>) {
+        self.mass_history.record(self.step, &self.cells, total_mass);
+    }
+
+    /// Clears the automatic pauses so that they trigger again on a new run.
+    pub fn rearm_pauses(&mut self) {
+        self.step_limit_reached = false;
+        self.radius_reached = false;
+        self.edge_reached = false;
+        self.pause_rules.rearm();
+    }
+
+    /// Pauses the run the first time `step` reaches `max_step`.
+    ///
+    /// Resuming afterwards continues past the limit until the next reset.
+    pub fn check_step_limit(&mut self) {
+        if self.step_limit_reached || self.max_step.is_none_or(|max_step| self.step < max_step) {
+            return;
+        }
+        self.is_running = false;
+        self.pending_steps = 0;
+        self.step_limit_reached = true;
+        tracing::info!("Paused at step {}", self.step);
+    }
+
+    /// Pauses the run the first time `radius` reaches `target_radius`, if enabled.
+    ///
+    /// Resuming afterwards continues past it until the next reset.
+    pub fn check_target_radius(&mut self) {
+        if self.radius_reached || !self.stop_at_radius || self.radius < self.target_radius {
+            return;
+        }
+        self.is_running = false;
+        self.pending_steps = 0;
+        self.radius_reached = true;
+        tracing::info!(
+            "Paused at step {}: the radius reached {}",
+            self.step,
+            self.target_radius
+        );
+    }
+
+    /// Pauses the run the first time a frozen cell comes within `edge_margin` of the edge.
+    ///
+    /// Only the cells in the margin are looked at.
+    pub fn check_edge(&mut self) {
+        let n = self.cells.shape()[0];
+        let margin = self.edge_margin.min(n / 2);
+        if self.edge_reached || margin == 0 {
+            return;
+        }
+        let near_edge = (0..n).any(|i| {
+            (0..margin)
+                .chain(n - margin..n)
+                .any(|j| self.cells[[i, j]] > 0.0 || self.cells[[j, i]] > 0.0)
+        });
+        if !near_edge {
+            return;
+        }
+        self.is_running = false;
+        self.pending_steps = 0;
+        self.edge_reached = true;
+        tracing::warn!(
+            "Paused at step {}: the crystal reached the grid edge",
+            self.step
+        );
+    }
+
+    /// Pauses the run the first time one of the enabled `pause_rules` fires.
+    pub fn check_pause_rules(&mut self) {
+        let Some(notice) = self.pause_rules.check(self.step, &self.cells) else {
+            return;
+        };
+        tracing::info!("Paused: {notice}");
+        self.is_running = false;
+        self.pending_steps = 0;
+    }
+}
+
+/// Largest distance from the center `(n / 2, n / 2)` to a frozen cell, in cell spacings.
+///
+/// Frozen cells are those with a positive mass in `cells`.
+pub fn crystal_radius(cells: &Array2<f32>) -> f32 {
+    let center = (cells.shape()[0] / 2) as isize;
+    let max_squared = cells
+        .indexed_iter()
+        .filter(|(_, &c)| c > 0.0)
+        .map(|((i, j), _)| {
+            // 斜交座標 (q, r) の平面上の距離は q² + qr + r²
+            let (q, r) = (i as isize - center, j as isize - center);
+            q * q + q * r + r * r
+        })
+        .max()
+        .unwrap_or(0);
+    (max_squared as f32).sqrt()
+}
+
+/// Number of frozen cells and the smallest positive and largest mass of `cells`, as
+/// [`visualization::value_range`], in one pass.
+fn frozen_cells_and_range(cells: &Array2<f32>) -> (usize, (f32, f32)) {
+    let (mut count, mut min, mut max) = (0, f32::INFINITY, 0.0f32);
+    for &c in cells {
+        max = max.max(c);
+        if c > 0.0 {
+            count += 1;
+            min = min.min(c);
+        }
+    }
+    // 凍ったセルがなければ value_range と同じく両端をそろえる
+    (count, (if count > 0 { min } else { max }, max))
+}
+
+impl Default for FieldInner {
+    fn default() -> Self {
+        Self::new(DEFAULT_GRID_SIZE)
+    }
+}
+
+/// Simulation speed measured from the step counter.
+#[derive(Resource, Default)]
+struct StepRate {
+    /// 前回測ったときのステップと経過秒数
+    last: Option<(u64, f64)>,
+    steps_per_second: f64,
+}
+
+/// Formats `duration` as `h:mm:ss`.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Seconds over which the step rate is averaged.
+const STEP_RATE_INTERVAL: f64 = 0.5;
+
+fn measure_step_rate(field: Res<Field>, time: Res<Time>, mut rate: ResMut<StepRate>) {
+    let step = field.0.read().step;
+    let now = time.elapsed_seconds_f64();
+    match rate.last {
+        Some((last_step, last_time)) if step >= last_step => {
+            if now - last_time >= STEP_RATE_INTERVAL {
+                rate.steps_per_second = (step - last_step) as f64 / (now - last_time);
+                rate.last = Some((step, now));
+            }
+        }
+        // リセットされたら測り直す
+        _ => {
+            rate.steps_per_second = 0.0;
+            rate.last = Some((step, now));
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn configure_ui(
+    mut contexts: EguiContexts,
+    field: Res<Field>,
+    diagnostics: Res<DiagnosticsStore>,
+    step_rate: Res<StepRate>,
+    mut saves: ResMut<BackgroundSaves>,
+    mut save_status: ResMut<SaveStatus>,
+    mut output_directory: ResMut<OutputDirectory>,
+    mut events: EventWriter<ControlEvent>,
+    mut state_path: ResMut<StatePath>,
+    mut export_settings: ResMut<ExportSettings>,
+    #[cfg(not(target_arch = "wasm32"))] mut gif_recorder: ResMut<gif::GifRecorder>,
+    mut view: visualization::ViewSettings,
+    mut last_max_step: Local<Option<u64>>,
+    // ファイルダイアログはメインスレッドで開く
+    _main_thread: Option<NonSend<bevy::core::NonSendMarker>>,
+) {
+    egui::Window::new("Control").show(contexts.ctx_mut(), |ui| {
+        let FieldInner {
+            is_running,
+            step,
+            model,
+            radius,
+            frozen_cells,
+            mass,
+            max_step,
+            step_limit_reached,
+            edge_reached,
+            run_clock,
+            ..
+        } = *field.0.read();
+        ui.horizontal(|ui| {
+            ui.add(egui::Label::new(format!("Step: {}", step)));
+            ui.add(egui::Label::new(format!("Radius: {radius:.1}")));
+            ui.label(format!("Cells: {frozen_cells}"))
+                .on_hover_text("Frozen cells, approximating the area of the crystal");
+            if let Some(mass) = mass {
+                let text = egui::RichText::new(format!(
+                    "Mass: {:.3} (Δ {:+.3e})",
+                    mass.total,
+                    mass.delta()
+                ));
+                ui.add(egui::Label::new(if mass.exceeded {
+                    text.color(egui::Color32::RED)
+                } else {
+                    text
+                }))
+                .on_hover_text("Total mass and its drift from step 0");
+            }
+            let mut selected = model;
+            egui::ComboBox::from_id_source("model")
+                .selected_text(selected.label())
+                .show_ui(ui, |ui| {
+                    for &model in Model::AVAILABLE {
+                        ui.selectable_value(&mut selected, model, model.label());
+                    }
+                });
+            if selected != model {
+                let mut field = field.0.write();
+                field.model = selected;
+                field.step = 0;
+                field.layer = None;
+                field.rearm_pauses();
+                field.pending_steps = 0;
+                tracing::info!("Switched to {selected:?}");
+            }
+            let mut selected = *view.color_map;
+            egui::ComboBox::from_id_source("color_map")
+                .selected_text(selected.label())
+                .show_ui(ui, |ui| {
+                    for color_map in visualization::ColorMap::ALL {
+                        ui.selectable_value(&mut selected, color_map, color_map.label());
+                    }
+                });
+            // 毎フレーム変更扱いにならないよう、変わったときだけ書き込む
+            if selected != *view.color_map {
+                *view.color_map = selected;
+            }
+            if model == Model::GravnerGriffeath {
+                let mut selected = field.0.read().shown_layer;
+                egui::ComboBox::from_id_source("shown_layer")
+                    .selected_text(selected.label())
+                    .show_ui(ui, |ui| {
+                        for layer in gravner_griffeath::FieldLayer::ALL {
+                            ui.selectable_value(&mut selected, layer, layer.label());
+                        }
+                    })
+                    .response
+                    .on_hover_text("Field of the model drawn in the 2D view");
+                if selected != field.0.read().shown_layer {
+                    field.0.write().shown_layer = selected;
+                }
+            }
+            let mut selected = *view.view_mode;
+            for mode in [
+                visualization::ViewMode::Flat,
+                visualization::ViewMode::Surface,
+            ] {
+                ui.radio_value(&mut selected, mode, mode.label());
+            }
+            if selected != *view.view_mode {
+                *view.view_mode = selected;
+            }
+            let mut front_overlay = *view.front_overlay;
+            ui.checkbox(&mut front_overlay.is_enabled, "Front")
+                .on_hover_text("Highlight the cells next to the crystal, where it grows next (2D)");
+            if front_overlay != *view.front_overlay {
+                *view.front_overlay = front_overlay;
+            }
+            let mut axes_overlay = *view.axes_overlay;
+            ui.checkbox(&mut axes_overlay.is_enabled, "Axes")
+                .on_hover_text("Draw the lattice directions from the center (2D)");
+            if axes_overlay != *view.axes_overlay {
+                *view.axes_overlay = axes_overlay;
+            }
+            let mut ruler = *view.ruler;
+            ui.checkbox(&mut ruler.is_enabled, "Ruler").on_hover_text(
+                "Click two cells to measure the distance between them, in cells and in \
+                 millimeters of the exported mesh (2D)",
+            );
+            if !ruler.is_enabled {
+                ruler = visualization::Ruler::default();
+            }
+            if ruler != *view.ruler {
+                *view.ruler = ruler;
+            }
+            let mut cell_borders = *view.cell_borders;
+            ui.checkbox(&mut cell_borders.is_enabled, "Borders")
+                .on_hover_text("Outline each cell and draw faint cells more opaque (2D)");
+            if cell_borders.is_enabled {
+                ui.add(
+                    egui::DragValue::new(&mut cell_borders.width)
+                        .range(0.02..=0.5)
+                        .speed(0.01),
+                )
+                .on_hover_text("Border width in cell spacings");
+            }
+            if cell_borders != *view.cell_borders {
+                *view.cell_borders = cell_borders;
+            }
+            if ui
+                .button("Fit")
+                .on_hover_text("Center the 2D view on the crystal and zoom to fit it (F)")
+                .clicked()
+            {
+                view.fit.send_default();
+            }
+        });
+        if model == Model::GravnerGriffeath {
+            paint_tool_ui(ui, &mut view.paint_tool);
+        }
+        ui.horizontal(|ui| {
+            let fps = diagnostics
+                .get(&FrameTimeDiagnosticsPlugin::FPS)
+                .and_then(|fps| fps.smoothed());
+            ui.label(match fps {
+                Some(fps) => format!("FPS: {fps:.1}"),
+                None => "FPS: -".to_string(),
+            });
+            ui.label(format!("Steps/s: {:.1}", step_rate.steps_per_second))
+                .on_hover_text("Steps the simulation actually runs per second");
+        });
+        ui.horizontal(|ui| {
+            ui.label(format!("Elapsed: {}", format_duration(run_clock.elapsed())))
+                .on_hover_text("Time spent running since the last reset, without pauses");
+            // 上限まで今の速さで進むとしたときの残り時間
+            let remaining = max_step
+                .filter(|&max_step| is_running && max_step > step)
+                .map(|max_step| (max_step - step) as f64)
+                .filter(|_| step_rate.steps_per_second > 0.0);
+            if let Some(remaining) = remaining {
+                let eta = Duration::from_secs_f64(remaining / step_rate.steps_per_second);
+                ui.label(format!("ETA: {}", format_duration(eta)))
+                    .on_hover_text("Time to the step limit at the current steps per second");
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui
+                .button(if is_running { "Pause" } else { "Resume" })
+                .clicked()
+            {
+                let mut field = field.0.write();
+                field.is_running = !field.is_running;
+            }
+            if ui
+                .add_enabled(!is_running, egui::Button::new("Step"))
+                .clicked()
+            {
+                events.send(ControlEvent::SingleStep);
+            }
+            if ui.button("Reset").clicked() {
+                let mut field = field.0.write();
+                if field.n != field.cells.shape()[0] {
+                    // グリッドを作り直すので一旦止める
+                    field.is_running = false;
+                }
+                field.rearm_pauses();
+                events.send(ControlEvent::Reset);
+                tracing::info!("Reset");
+            }
+            ui.add(
+                egui::DragValue::new(&mut field.0.write().n)
+                    .range(16..=2000)
+                    .prefix("n: "),
+            );
+        });
+        ui.horizontal(|ui| {
+            let mut enabled = max_step.is_some();
+            let mut limit = max_step.unwrap_or(last_max_step.unwrap_or(DEFAULT_MAX_STEP));
+            let changed = ui.checkbox(&mut enabled, "stop at step").changed()
+                | ui.add(egui::DragValue::new(&mut limit).range(1..=u64::MAX))
+                    .changed();
+            if changed {
+                field.0.write().max_step = enabled.then_some(limit);
+                *last_max_step = Some(limit);
+            }
+            ui.checkbox(
+                &mut export_settings.save_when_stopped,
+                "save STL and CSV when stopped",
+            );
+            if step_limit_reached {
+                ui.label("(reached)");
+            }
+        });
+        ui.horizontal(|ui| {
+            let mut field = field.0.write();
+            ui.checkbox(&mut field.stop_at_radius, "stop at radius");
+            ui.add(
+                egui::DragValue::new(&mut field.target_radius)
+                    .range(1.0..=f32::MAX)
+                    .speed(1.0),
+            )
+            .on_hover_text("In cell spacings, as the radius shown above");
+            if field.radius_reached {
+                ui.label("(reached)");
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::DragValue::new(&mut field.0.write().edge_margin)
+                    .range(0..=100)
+                    .prefix("stop within ")
+                    .suffix(" cells of the edge"),
+            );
+            if edge_reached {
+                ui.colored_label(
+                    egui::Color32::RED,
+                    "The crystal reached the edge; enlarge n and reset",
+                );
+            }
+        });
+        pause_rules::rules_ui(ui, &field);
+        ui.horizontal(|ui| {
+            if ui.button("Save All").clicked() {
+                // STL と CSV は Save イベントで、PNG はここで同じ時刻を付けて書く
+                let now = chrono::Local::now();
+                events.send(ControlEvent::Save(now));
+                let (cells, lattice) = {
+                    let field = field.0.read();
+                    (field.cells.clone(), field.lattice)
+                };
+                let output_directory = output_directory.clone();
+                saves.spawn("PNG", move || {
+                    save_png(&output_directory, &file_name(now, "png"), &cells, lattice)
+                });
+            }
+            if ui.button("Save STL").clicked() {
+                if let Some(path) = file_dialog::pick_save_path(
+                    &output_directory,
+                    &file_name(chrono::Local::now(), "stl"),
+                ) {
+                    events.send(ControlEvent::SaveAs(path));
+                }
+            }
+            if saves.is_busy() {
+                ui.spinner().on_hover_text("Saving in the background");
+                if let Some(progress) = saves.progress() {
+                    ui.add(
+                        egui::ProgressBar::new(progress)
+                            .desired_width(80.0)
+                            .show_percentage(),
+                    )
+                    .on_hover_text("Building the mesh");
+                }
+            }
+            ui.radio_value(
+                &mut export_settings.stl_format,
+                stl::StlFormat::Binary,
+                "binary",
+            );
+            ui.radio_value(
+                &mut export_settings.stl_format,
+                stl::StlFormat::Ascii,
+                "ASCII",
+            );
+            // ブラウザではファイルに書けないので、ダウンロードできる STL と PNG のほかは保存しない
+            #[cfg(not(target_arch = "wasm32"))]
+            {
+                if ui.button("Save OBJ").clicked() {
+                    let result = file_dialog::save_as(
+                        &output_directory,
+                        &file_name(chrono::Local::now(), "obj"),
+                        |path| {
+                            obj::write_to_obj(
+                                &field,
+                                path,
+                                export_settings.xy_scale,
+                                export_settings.z_scale,
+                                export_settings.relief(),
+                                export_settings.wedge,
+                            )
+                        },
+                    );
+                    if let Some(result) = result {
+                        save_status.report("OBJ", result);
+                    }
+                }
+                if ui.button("Save SVG").clicked() {
+                    let fill = export_settings.svg_fill;
+                    let result = file_dialog::save_as(
+                        &output_directory,
+                        &file_name(chrono::Local::now(), "svg"),
+                        |path| {
+                            let field = field.0.read();
+                            svg::write_to_svg(&field.cells, field.lattice, path, fill)
+                        },
+                    );
+                    if let Some(result) = result {
+                        save_status.report("SVG", result);
+                    }
+                }
+                ui.checkbox(&mut export_settings.svg_fill, "fill");
+            }
+            if ui.button("Save PNG").clicked() {
+                let result = file_dialog::save_bytes_as(
+                    &output_directory,
+                    &file_name(chrono::Local::now(), "png"),
+                    || {
+                        let field = field.0.read();
+                        png::png_bytes(&field.cells, field.lattice, None)
+                    },
+                );
+                if let Some(result) = result {
+                    save_status.report("PNG", result);
+                }
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            {
+                if ui.button("Save Heightmap").clicked() {
+                    let scale = export_settings.heightmap_scale;
+                    let name = format!(
+                        "snowflake-heightmap-{}.png",
+                        chrono::Local::now().format(TIMESTAMP_FORMAT)
+                    );
+                    let result = file_dialog::save_as(&output_directory, &name, |path| {
+                        let field = field.0.read();
+                        png::write_heightmap_png(&field.cells, field.lattice, path, scale)
+                    });
+                    if let Some(result) = result {
+                        save_status.report("heightmap", result);
+                    }
+                }
+                ui.add(
+                    egui::DragValue::new(&mut export_settings.heightmap_scale)
+                        .range(0.5..=20.0)
+                        .speed(0.1)
+                        .suffix(" px/cell"),
+                )
+                .on_hover_text("Pixels per cell spacing of the heightmap");
+            }
+        });
+        #[cfg(not(target_arch = "wasm32"))]
+        ui.horizontal(|ui| {
+            if ui.button("Save Cells CSV").clicked() {
+                let format = export_settings.cells_csv_format;
+                let name = format!(
+                    "snowflake-cells-{}.csv",
+                    chrono::Local::now().format(TIMESTAMP_FORMAT)
+                );
+                let result = file_dialog::save_as(&output_directory, &name, |path| {
+                    cells_csv::write_cells_to_csv(&field.0.read().cells, path, format)
+                });
+                if let Some(result) = result {
+                    save_status.report("cells CSV", result);
+                }
+            }
+            ui.radio_value(
+                &mut export_settings.cells_csv_format,
+                cells_csv::CellsCsvFormat::Sparse,
+                "sparse",
+            );
+            ui.radio_value(
+                &mut export_settings.cells_csv_format,
+                cells_csv::CellsCsvFormat::Dense,
+                "dense",
+            );
+        });
+        ui.add(
+            egui::Slider::new(&mut export_settings.xy_scale, 0.001..=1.0)
+                .text("mesh xy scale")
+                .logarithmic(true),
+        );
+        ui.add(
+            egui::Slider::new(&mut export_settings.z_scale, 0.001..=10.0)
+                .text("mesh z scale")
+                .logarithmic(true),
+        );
+        ui.horizontal(|ui| {
+            let geometry = &mut export_settings.stl_geometry;
+            if ui
+                .radio(*geometry == stl::StlGeometry::Solid, "solid")
+                .clicked()
+            {
+                *geometry = stl::StlGeometry::Solid;
+            }
+            let is_outline = matches!(geometry, stl::StlGeometry::Outline { .. });
+            if ui.radio(is_outline, "outline").clicked() && !is_outline {
+                *geometry = stl::StlGeometry::Outline { width: 0.5 };
+            }
+            if let stl::StlGeometry::Outline { width } = geometry {
+                ui.add(
+                    egui::DragValue::new(width)
+                        .range(0.05..=10.0)
+                        .speed(0.01)
+                        .prefix("ribbon width: ")
+                        .suffix(" cells"),
+                );
+            }
+            let is_hollow = matches!(geometry, stl::StlGeometry::Hollow { .. });
+            if ui
+                .radio(is_hollow, "hollow")
+                .on_hover_text("Leave a closed cavity inside the solid relief")
+                .clicked()
+                && !is_hollow
+            {
+                *geometry = stl::StlGeometry::Hollow { wall: 2.0 };
+            }
+            if let stl::StlGeometry::Hollow { wall } = geometry {
+                ui.add(
+                    egui::DragValue::new(wall)
+                        .range(0.1..=50.0)
+                        .speed(0.05)
+                        .prefix("wall: ")
+                        .suffix(" cells"),
+                );
+            }
+            ui.checkbox(&mut export_settings.wedge, "60° wedge")
+                .on_hover_text("Export only one sixth of the crystal, cut along the lattice axes");
+        });
+        ui.horizontal(|ui| {
+            let mapping = &mut export_settings.height_mapping;
+            egui::ComboBox::from_label("mesh height")
+                .selected_text(mapping.label())
+                .show_ui(ui, |ui| {
+                    for option in [
+                        stl::HeightMapping::Linear,
+                        stl::HeightMapping::Sqrt,
+                        stl::HeightMapping::Log,
+                        stl::HeightMapping::Clamped(1.0),
+                    ] {
+                        // 上限は選び直しても保つ
+                        let selected =
+                            std::mem::discriminant(mapping) == std::mem::discriminant(&option);
+                        if ui.selectable_label(selected, option.label()).clicked() && !selected {
+                            *mapping = option;
+                        }
+                    }
+                });
+            if let stl::HeightMapping::Clamped(max) = mapping {
+                ui.add(
+                    egui::DragValue::new(max)
+                        .range(0.01..=100.0)
+                        .speed(0.01)
+                        .prefix("max mass: "),
+                );
+            }
+            ui.checkbox(&mut export_settings.terraces, "terraces")
+                .on_hover_text("Snap the heights to evenly spaced steps, like a contour map");
+            if export_settings.terraces {
+                ui.add(
+                    egui::DragValue::new(&mut export_settings.terrace_levels)
+                        .range(2..=100)
+                        .suffix(" levels"),
+                );
+            }
+            ui.add(
+                egui::DragValue::new(&mut export_settings.smoothing)
+                    .range(0..=20)
+                    .prefix("smoothing: ")
+                    .suffix(" rounds"),
+            )
+            .on_hover_text("Average the heights with the neighbors to soften the stair steps");
+            ui.checkbox(&mut export_settings.thicken, "thicken")
+                .on_hover_text("Widen the arms too thin for the nozzle of a 3D printer");
+            if export_settings.thicken {
+                let width = export_settings.min_feature * export_settings.xy_scale;
+                ui.add(
+                    egui::DragValue::new(&mut export_settings.min_feature)
+                        .range(1.0..=50.0)
+                        .speed(0.1)
+                        .prefix("min width: ")
+                        .suffix(" cells"),
+                )
+                .on_hover_text(format!("{width:.2} mm at the mesh xy scale"));
+            }
+        });
+        #[cfg(not(target_arch = "wasm32"))]
+        ui.horizontal(|ui| {
+            if gif_recorder.is_recording {
+                if ui
+                    .button(format!("Stop GIF ({})", gif_recorder.frame_count()))
+                    .clicked()
+                {
+                    // キャンセルしたら録画を続ける
+                    let result = file_dialog::save_as(
+                        &output_directory,
+                        &file_name(chrono::Local::now(), "gif"),
+                        |path| gif_recorder.stop(path),
+                    );
+                    if let Some(result) = result {
+                        save_status.report("GIF", result);
+                    }
+                }
+            } else if ui.button("Record GIF").clicked() {
+                gif_recorder.start();
+            }
+            ui.add(
+                egui::DragValue::new(&mut gif_recorder.interval)
+                    .range(1..=10000)
+                    .prefix("every ")
+                    .suffix(" steps"),
+            );
+            ui.add(
+                egui::DragValue::new(&mut gif_recorder.max_frames)
+                    .range(1..=1000)
+                    .prefix("max ")
+                    .suffix(" frames"),
+            );
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Save State").clicked() {
+                if let Some(path) = file_dialog::pick_save_path(
+                    &output_directory,
+                    &file_name(chrono::Local::now(), "state"),
+                ) {
+                    state_path.0 = path.display().to_string();
+                    events.send(ControlEvent::SaveState(path));
+                }
+            }
+            ui.text_edit_singleline(&mut state_path.0);
+            if ui.button("Load State").clicked() {
+                let current = (!state_path.0.is_empty()).then(|| PathBuf::from(&state_path.0));
+                if let Some(path) = file_dialog::pick_open_path(current.as_deref(), &["state"]) {
+                    state_path.0 = path.display().to_string();
+                    events.send(ControlEvent::LoadState(path));
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            #[cfg(not(target_arch = "wasm32"))]
+            if ui.button("Save NPY").clicked() {
+                let result = file_dialog::save_as(
+                    &output_directory,
+                    &file_name(chrono::Local::now(), "npy"),
+                    |path| npy::write_to_npy(&field.0.read().cells, path),
+                );
+                if let Some(result) = result {
+                    save_status.report("NPY", result);
+                }
+            }
+            if ui.button("Save NPZ").clicked() {
+                if let Some(path) = file_dialog::pick_save_path(
+                    &output_directory,
+                    &file_name(chrono::Local::now(), "npz"),
+                ) {
+                    events.send(ControlEvent::SaveNpz(path));
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Output directory:");
+            let mut dir = output_directory.0.display().to_string();
+            if ui.text_edit_singleline(&mut dir).changed() {
+                output_directory.0 = PathBuf::from(dir);
+            }
+        });
+        save_status.show(ui);
+    });
+}
+
+/// Choice of the painting tool, the amount of vapor it adds, the radius of the brush and its
+/// mirroring.
+fn paint_tool_ui(ui: &mut egui::Ui, paint_tool: &mut visualization::PaintTool) {
+    let mut edited = *paint_tool;
+    ui.horizontal(|ui| {
+        ui.label("Paint:")
+            .on_hover_text("Left-click or drag on the 2D view to edit the cells");
+        ui.radio_value(&mut edited.kind, None, "off");
+        let vapor = PaintKind::Vapor(edited.vapor);
+        ui.radio_value(&mut edited.kind, Some(vapor), vapor.label());
+        for kind in [PaintKind::Freeze, PaintKind::Erase] {
+            ui.radio_value(&mut edited.kind, Some(kind), kind.label());
+        }
+        ui.add(
+            egui::DragValue::new(&mut edited.vapor)
+                .range(0.0..=10.0)
+                .speed(0.01),
+        )
+        .on_hover_text("Vapor added to each painted cell");
+        ui.add(
+            egui::DragValue::new(&mut edited.radius)
+                .range(0..=50)
+                .prefix("radius: "),
+        )
+        .on_hover_text("Cells within this many steps of the cursor are painted; 0 paints one");
+    });
+    ui.horizontal(|ui| {
+        ui.label("Mirror:").on_hover_text(
+            "Repeat each stroke rotated by 60° around the center of the grid, \
+             and with 12-fold also reflected",
+        );
+        for mirror in [Mirror::Off, Mirror::Rotations, Mirror::Reflections] {
+            ui.radio_value(&mut edited.mirror, mirror, mirror.label());
+        }
+    });
+    // 量を変えたら選択中の道具にも反映する
+    if let Some(PaintKind::Vapor(amount)) = &mut edited.kind {
+        *amount = edited.vapor;
+    }
+    if edited != *paint_tool {
+        *paint_tool = edited;
+    }
+}
+
+/// Writes the STL with the current export settings on [`ControlEvent::Save`] and
+/// [`ControlEvent::SaveAs`].
+///
+/// The parameter log of the same save is written by the model's plugin, with the same name.
+#[cfg(not(target_arch = "wasm32"))]
+fn save_stl(
+    mut events: EventReader<ControlEvent>,
+    field: Res<Field>,
+    export_settings: Res<ExportSettings>,
+    output_directory: Res<OutputDirectory>,
+    mut saves: ResMut<BackgroundSaves>,
+) {
+    for event in events.read() {
+        // ダイアログで選んだファイルは上書きを確認済み
+        let (path, confirmed) = match event {
+            ControlEvent::Save(now) => (output_directory.path(&file_name(*now, "stl")), false),
+            ControlEvent::SaveAs(path) => (Ok(path.clone()), true),
+            _ => continue,
+        };
+        // ロックは複製する間だけ取り、メッシュの生成と書き込みは別スレッドで行う
+        let field = field.0.read();
+        let cells = field.cells.clone();
+        let options = export_settings.stl_options(field.lattice);
+        drop(field);
+        saves.spawn_with_progress("STL", move |progress| {
+            let path = path?;
+            let write = |path: &Path| {
+                stl::write_to_stl(&cells, path, options, &|fraction| progress.set(fraction))
+            };
+            if confirmed {
+                file_dialog::replace(&path, write)?;
+            } else {
+                write(&path)?;
+            }
+            Ok(path)
+        });
+    }
+}
+
+/// Writes the PNG of "Save All" as `name` in the output directory.
+#[cfg(not(target_arch = "wasm32"))]
+fn save_png(
+    output_directory: &OutputDirectory,
+    name: &str,
+    cells: &Array2<f32>,
+    lattice: gravner_griffeath::Lattice,
+) -> std::io::Result<PathBuf> {
+    output_directory.save(name, |path| png::write_to_png(cells, lattice, path, None))
+}
+
+/// Downloads the PNG of "Save All" as `name`, since the browser cannot write files.
+#[cfg(target_arch = "wasm32")]
+fn save_png(
+    _output_directory: &OutputDirectory,
+    name: &str,
+    cells: &Array2<f32>,
+    lattice: gravner_griffeath::Lattice,
+) -> std::io::Result<PathBuf> {
+    download::download(name, &png::png_bytes(cells, lattice, None)?)?;
+    Ok(name.into())
+}
+
+/// Downloads the STL with the current export settings on [`ControlEvent::Save`] and
+/// [`ControlEvent::SaveAs`], since the browser can neither write files nor run threads.
+#[cfg(target_arch = "wasm32")]
+fn save_stl(
+    mut events: EventReader<ControlEvent>,
+    field: Res<Field>,
+    export_settings: Res<ExportSettings>,
+    mut save_status: ResMut<SaveStatus>,
+) {
+    for event in events.read() {
+        let name = match event {
+            ControlEvent::Save(now) => file_name(*now, "stl"),
+            ControlEvent::SaveAs(path) => path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            _ => continue,
+        };
+        let field = field.0.read();
+        let result = stl::stl_bytes(
+            &field.cells,
+            export_settings.stl_options(field.lattice),
+            &|_| {},
+        )
+        .and_then(|bytes| download::download(&name, &bytes));
+        save_status.report("STL", result.map(|()| PathBuf::from(name)));
+    }
+}
+
+/// Saves the STL and the log once when the step limit or the target radius pauses the run, if
+/// enabled.
+fn auto_save_when_stopped(
+    field: Res<Field>,
+    export_settings: Res<ExportSettings>,
+    mut events: EventWriter<ControlEvent>,
+    mut was_reached: Local<(bool, bool)>,
+) {
+    let reached = {
+        let field = field.0.read();
+        (field.step_limit_reached, field.radius_reached)
+    };
+    let newly_reached = (reached.0 && !was_reached.0) || (reached.1 && !was_reached.1);
+    if newly_reached && export_settings.save_when_stopped {
+        events.send(ControlEvent::Save(chrono::Local::now()));
+    }
+    *was_reached = reached;
+}
+
+fn set_window_title(mut window_query: Query<&mut Window, With<PrimaryWindow>>) {
+    if let Ok(mut window) = window_query.get_single_mut() {
+        window.title = "Snowflake Simulator".to_string();
+    }
+}
@@ -0,0 +1,140 @@
+//! Contact sheets that tile the crystals of a parameter sweep into one labeled image.
+
+use std::{fs::OpenOptions, io::BufWriter, path::Path};
+
+use ab_glyph::{Font as _, FontRef, PxScale, ScaleFont as _};
+use bevy_egui::egui;
+use image::{imageops, GrayImage, ImageFormat, Luma};
+use ndarray::Array2;
+
+use crate::{
+    gravner_griffeath::Lattice,
+    png::{extent, rasterize},
+    visualization::normalize,
+};
+
+/// Width of each crystal on the sheet, in pixels.
+pub const TILE_WIDTH: u32 = 240;
+/// Height of the strip under each crystal that holds its label, in pixels.
+const LABEL_HEIGHT: u32 = 28;
+/// Size of the label text, in pixels.
+const LABEL_SIZE: f32 = 18.0;
+/// Font of the labels, one of those egui ships with.
+const LABEL_FONT: &str = "Hack";
+
+/// Tiles the crystals on `lattice` into rows of `columns`, in order, each drawn as in
+/// "Save PNG" at [`TILE_WIDTH`] pixels wide with its label centered below it.
+///
+/// The labels are left out, with a warning, if the font is not available.
+pub fn contact_sheet(
+    tiles: &[(String, Array2<f32>)],
+    lattice: Lattice,
+    columns: usize,
+) -> GrayImage {
+    let columns = columns.clamp(1, tiles.len().max(1));
+    let rows = tiles.len().div_ceil(columns);
+    let crystals: Vec<GrayImage> = tiles
+        .iter()
+        .map(|(_, cells)| {
+            // 切り上げで隣の枠にはみ出さないよう少し縮める
+            let scale = (TILE_WIDTH as f32 - 0.5) / extent(cells.shape()[0], lattice).x;
+            rasterize(&normalize(cells), lattice, Some(scale))
+        })
+        .collect();
+    let tile_height = crystals.iter().map(GrayImage::height).max().unwrap_or(0) + LABEL_HEIGHT;
+    let mut sheet = GrayImage::new(TILE_WIDTH * columns as u32, tile_height * rows as u32);
+    let fonts = egui::FontDefinitions::default();
+    let font = fonts
+        .font_data
+        .get(LABEL_FONT)
+        .and_then(|data| FontRef::try_from_slice(&data.font).ok());
+    if font.is_none() {
+        tracing::warn!("Font {LABEL_FONT} is not available, leaving the labels out");
+    }
+    for (k, ((label, _), crystal)) in tiles.iter().zip(&crystals).enumerate() {
+        let x = (k % columns) as u32 * TILE_WIDTH;
+        let y = (k / columns) as u32 * tile_height;
+        // 高さの違う結晶はラベルの上に揃える
+        let top = y + tile_height - LABEL_HEIGHT - crystal.height();
+        imageops::replace(&mut sheet, crystal, x as i64, top as i64);
+        if let Some(font) = &font {
+            draw_label(&mut sheet, font, label, x, y + tile_height - LABEL_HEIGHT);
+        }
+    }
+    sheet
+}
+
+/// Writes the [`contact_sheet`] of the crystals as a grayscale PNG.
+pub fn write_contact_sheet(
+    tiles: &[(String, Array2<f32>)],
+    lattice: Lattice,
+    columns: usize,
+    path: &Path,
+) -> std::io::Result<()> {
+    let sheet = contact_sheet(tiles, lattice, columns);
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    sheet
+        .write_to(&mut BufWriter::new(file), ImageFormat::Png)
+        .map_err(std::io::Error::other)
+}
+
+/// Draws `text` in white, centered in the label strip of the tile whose top left corner of
+/// the strip is `(x, y)`.
+fn draw_label(sheet: &mut GrayImage, font: &FontRef, text: &str, x: u32, y: u32) {
+    let font = font.as_scaled(PxScale::from(LABEL_SIZE));
+    let width: f32 = text.chars().map(|c| font.h_advance(font.glyph_id(c))).sum();
+    let mut caret = x as f32 + (TILE_WIDTH as f32 - width).max(0.0) / 2.0;
+    let baseline = y as f32 + (LABEL_HEIGHT as f32 + font.ascent() + font.descent()) / 2.0;
+    for c in text.chars() {
+        let id = font.glyph_id(c);
+        let glyph = id.with_scale_and_position(font.scale(), ab_glyph::point(caret, baseline));
+        caret += font.h_advance(id);
+        let Some(outline) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outline.px_bounds();
+        outline.draw(|gx, gy, coverage| {
+            let (px, py) = (
+                bounds.min.x as i64 + gx as i64,
+                bounds.min.y as i64 + gy as i64,
+            );
+            // はみ出した分は描かない
+            if (x as i64..(x + TILE_WIDTH) as i64).contains(&px)
+                && (0..sheet.height() as i64).contains(&py)
+            {
+                let pixel = sheet.get_pixel_mut(px as u32, py as u32);
+                *pixel = Luma([pixel.0[0].max((coverage * 255.0).round() as u8)]);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crystal(n: usize) -> Array2<f32> {
+        let mut cells = Array2::zeros((n, n));
+        cells[[n / 2, n / 2]] = 1.0;
+        cells
+    }
+
+    #[test]
+    fn tiles_fill_rows_of_the_given_columns() {
+        let tiles: Vec<_> = (0..5).map(|k| (format!("β=1.{k}"), crystal(21))).collect();
+        let sheet = contact_sheet(&tiles, Lattice::Hex, 3);
+        assert_eq!(sheet.width(), 3 * TILE_WIDTH);
+        let tile_height = sheet.height() / 2;
+        assert_eq!(sheet.height(), 2 * tile_height);
+        // 6枚目の場所は空いている
+        let empty = (2 * TILE_WIDTH..3 * TILE_WIDTH)
+            .flat_map(|x| (tile_height..2 * tile_height).map(move |y| (x, y)))
+            .all(|(x, y)| sheet.get_pixel(x, y).0[0] == 0);
+        assert!(empty);
+        // ラベルの帯に文字が描かれる
+        let label = (0..TILE_WIDTH)
+            .flat_map(|x| (tile_height - LABEL_HEIGHT..tile_height).map(move |y| (x, y)))
+            .any(|(x, y)| sheet.get_pixel(x, y).0[0] > 128);
+        assert!(label);
+    }
+}
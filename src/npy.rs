@@ -0,0 +1,78 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use ndarray::Array2;
+use ndarray_npy::ReadNpyExt as _;
+#[cfg(not(target_arch = "wasm32"))]
+use ndarray_npy::{NpzWriter, WriteNpyExt as _};
+
+/// Writes the crystal mass of the cells as a NumPy `.npy` array of `float32`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_to_npy(cells: &Array2<f32>, path: &Path) -> std::io::Result<()> {
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    cells.write_npy(file).map_err(std::io::Error::other)
+}
+
+/// Reads a `float32` array written by [`write_to_npy`].
+pub fn read_npy(path: &Path) -> std::io::Result<Array2<f32>> {
+    let file = std::fs::File::open(path)?;
+    Array2::read_npy(file).map_err(std::io::Error::other)
+}
+
+/// Writes the four fields of the Gravner-Griffeath model as arrays `a` (bool) and `b`, `c`, `d`
+/// (`float32`) of a NumPy `.npz` archive.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_fields_to_npz(
+    path: &Path,
+    a: &Array2<bool>,
+    b: &Array2<f32>,
+    c: &Array2<f32>,
+    d: &Array2<f32>,
+) -> std::io::Result<()> {
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let mut npz = NpzWriter::new(file);
+    npz.add_array("a", a).map_err(std::io::Error::other)?;
+    for (name, array) in [("b", b), ("c", c), ("d", d)] {
+        npz.add_array(name, array).map_err(std::io::Error::other)?;
+    }
+    npz.finish().map_err(std::io::Error::other)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use ndarray::array;
+    use ndarray_npy::NpzReader;
+
+    use super::*;
+
+    #[test]
+    fn npy_round_trips_the_cells() {
+        let path = std::env::temp_dir().join(format!("snowflake-test-{}.npy", std::process::id()));
+        let cells = array![[0.0, 1.5], [f32::MIN_POSITIVE, 3.0]];
+        write_to_npy(&cells, &path).unwrap();
+        let read = read_npy(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap(), cells);
+    }
+
+    #[test]
+    fn npz_round_trips_the_fields() {
+        let path = std::env::temp_dir().join(format!("snowflake-test-{}.npz", std::process::id()));
+        let a = array![[false, true], [true, false]];
+        let b = array![[0.0, 0.25], [0.5, 0.75]];
+        let c = array![[1.0, 1.5], [f32::MIN_POSITIVE, 3.0]];
+        let d = array![[0.1, 0.2], [0.3, 0.4]];
+        write_fields_to_npz(&path, &a, &b, &c, &d).unwrap();
+        let mut npz = NpzReader::new(File::open(&path).unwrap()).unwrap();
+        let read_a: Array2<bool> = npz.by_name("a").unwrap();
+        let read_c: Array2<f32> = npz.by_name("c").unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read_a, a);
+        // 値はビット単位で保たれる
+        assert_eq!(read_c, c);
+    }
+}
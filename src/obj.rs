@@ -0,0 +1,63 @@
+use std::{
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use fnv::FnvHashMap;
+use stl_io::Triangle;
+
+use crate::{
+    stl::{cells_to_triangles, export_cells, Relief},
+    Field,
+};
+
+pub fn write_to_obj(
+    field: &Field,
+    path: &Path,
+    xy_scale: f32,
+    z_scale: f32,
+    relief: Relief,
+    is_wedge: bool,
+) -> std::io::Result<()> {
+    let ((cells, relief), lattice) = {
+        let field = field.0.read();
+        (export_cells(&field.cells, relief, is_wedge), field.lattice)
+    };
+    let triangles = cells_to_triangles(&cells, lattice, xy_scale, z_scale, relief, &|_| {});
+    let (vertices, faces) = index_vertices(&triangles);
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "o snowflake")?;
+    for [x, y, z] in &vertices {
+        writeln!(writer, "v {x} {y} {z}")?;
+    }
+    for [a, b, c] in &faces {
+        // OBJの頂点番号は1始まり
+        writeln!(writer, "f {} {} {}", a + 1, b + 1, c + 1)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Collapses identical vertices of the triangles into an indexed vertex and face list.
+fn index_vertices(triangles: &[Triangle]) -> (Vec<[f32; 3]>, Vec<[usize; 3]>) {
+    let mut vertices = Vec::new();
+    // 座標のビット列をキーにして頂点番号を引く
+    let mut indices: FnvHashMap<[u32; 3], usize> = FnvHashMap::default();
+    let faces = triangles
+        .iter()
+        .map(|triangle| {
+            triangle.vertices.map(|vertex| {
+                let position = [vertex[0], vertex[1], vertex[2]];
+                *indices
+                    .entry(position.map(f32::to_bits))
+                    .or_insert_with(|| {
+                        vertices.push(position);
+                        vertices.len() - 1
+                    })
+            })
+        })
+        .collect();
+    (vertices, faces)
+}
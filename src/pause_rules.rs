@@ -0,0 +1,234 @@
+//! Rules that pause the run when something worth a look happens, checked by the worker after
+//! each step.
+
+use bevy_egui::egui;
+use ndarray::Array2;
+
+use crate::{gravner_griffeath::Lattice, symmetry::symmetry_error, Field};
+
+/// Steps between the checks of [`Trigger::SymmetryError`], which looks at every cell five times.
+const SYMMETRY_CHECK_INTERVAL: u64 = 10;
+
+/// Facet radius below which [`branching_ratio`] does not tell plates from arms yet.
+const MIN_FACET_RADIUS: f32 = 3.0;
+
+/// What a [`PauseRule`] waits for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+    /// the tips have outgrown the facets by this [`branching_ratio`]
+    Branching(f32),
+    /// the crystal mass has reached this
+    Mass(f32),
+    /// the [`symmetry_error`] has gone beyond this
+    SymmetryError(f32),
+}
+
+impl Trigger {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Trigger::Branching(_) => "arms form, tip/facet ≥",
+            Trigger::Mass(_) => "crystal mass ≥",
+            Trigger::SymmetryError(_) => "symmetry error >",
+        }
+    }
+
+    fn threshold_mut(&mut self) -> &mut f32 {
+        match self {
+            Trigger::Branching(x) | Trigger::Mass(x) | Trigger::SymmetryError(x) => x,
+        }
+    }
+
+    /// Whether the trigger fires for `cells` at `step`.
+    fn is_met(&self, step: u64, cells: &Array2<f32>) -> bool {
+        match *self {
+            Trigger::Branching(ratio) => branching_ratio(cells).is_some_and(|r| r >= ratio),
+            Trigger::Mass(mass) => cells.iter().filter(|&&c| c > 0.0).sum::<f32>() >= mass,
+            Trigger::SymmetryError(error) => {
+                step.is_multiple_of(SYMMETRY_CHECK_INTERVAL) && symmetry_error(cells) > error
+            }
+        }
+    }
+
+    /// What happened, for the notice shown when the run is paused.
+    fn describe(&self) -> String {
+        match self {
+            Trigger::Branching(ratio) => format!("arms formed (tip/facet ≥ {ratio})"),
+            Trigger::Mass(mass) => format!("the crystal mass reached {mass}"),
+            Trigger::SymmetryError(error) => format!("the symmetry error exceeded {error}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PauseRule {
+    pub is_enabled: bool,
+    pub trigger: Trigger,
+    /// set when the rule has paused the run, until the next reset
+    fired: bool,
+}
+
+impl PauseRule {
+    fn new(trigger: Trigger) -> Self {
+        Self {
+            is_enabled: false,
+            trigger,
+            fired: false,
+        }
+    }
+}
+
+/// Each rule pauses the run once, the first time its trigger fires; resuming continues past it
+/// until the next reset.
+#[derive(Clone, Debug)]
+pub struct PauseRules {
+    pub rules: Vec<PauseRule>,
+    /// what the last rule that fired saw, for the UI
+    pub notice: Option<String>,
+}
+
+impl Default for PauseRules {
+    fn default() -> Self {
+        Self {
+            rules: vec![
+                PauseRule::new(Trigger::Branching(1.4)),
+                PauseRule::new(Trigger::Mass(1000.0)),
+                PauseRule::new(Trigger::SymmetryError(0.05)),
+            ],
+            notice: None,
+        }
+    }
+}
+
+impl PauseRules {
+    /// Fires the first enabled rule whose trigger is met, returning its notice.
+    pub fn check(&mut self, step: u64, cells: &Array2<f32>) -> Option<&str> {
+        let rule = self
+            .rules
+            .iter_mut()
+            .filter(|rule| rule.is_enabled && !rule.fired)
+            .find(|rule| rule.trigger.is_met(step, cells))?;
+        rule.fired = true;
+        self.notice = Some(format!("Step {step}: {}", rule.trigger.describe()));
+        self.notice.as_deref()
+    }
+
+    /// Clears the fired rules so that they trigger again on a new run.
+    pub fn rearm(&mut self) {
+        for rule in &mut self.rules {
+            rule.fired = false;
+        }
+        self.notice = None;
+    }
+}
+
+/// How far the six tips reach beyond the facets between them.
+///
+/// This is the largest distance from `(n / 2, n / 2)` to a frozen cell within 5° of the lattice
+/// axes, divided by that within 5° of the directions halfway between them. A hexagonal plate
+/// has 2/√3 ≈ 1.15, and the ratio grows as arms form. `None` while the crystal is too small to
+/// tell.
+pub fn branching_ratio(cells: &Array2<f32>) -> Option<f32> {
+    let center = (cells.shape()[0] / 2) as f32;
+    let (mut tip, mut facet) = (0.0f32, 0.0f32);
+    for ((i, j), &c) in cells.indexed_iter() {
+        if c <= 0.0 {
+            continue;
+        }
+        let p = Lattice::Hex.position(i as f32 - center, j as f32 - center);
+        let distance = p.length();
+        // 軸からの角度を 0..60° に畳む
+        let angle = p.y.atan2(p.x).to_degrees().rem_euclid(60.0);
+        if !(5.0..=55.0).contains(&angle) {
+            tip = tip.max(distance);
+        } else if (angle - 30.0).abs() <= 5.0 {
+            facet = facet.max(distance);
+        }
+    }
+    (facet >= MIN_FACET_RADIUS).then(|| tip / facet)
+}
+
+/// Checkboxes and thresholds of the [`PauseRules`], with the notice of the last one that fired.
+pub fn rules_ui(ui: &mut egui::Ui, field: &Field) {
+    let rules = field.0.read().pause_rules.clone();
+    ui.label("Pause when");
+    for (k, rule) in rules.rules.iter().enumerate() {
+        ui.horizontal(|ui| {
+            let mut edited = rule.clone();
+            let (range, speed) = match rule.trigger {
+                Trigger::Branching(_) => (1.0..=10.0, 0.01),
+                Trigger::Mass(_) => (0.0..=f32::MAX, 10.0),
+                Trigger::SymmetryError(_) => (0.0..=1.0, 0.001),
+            };
+            let changed = ui
+                .checkbox(&mut edited.is_enabled, rule.trigger.label())
+                .changed()
+                | ui.add(
+                    egui::DragValue::new(edited.trigger.threshold_mut())
+                        .range(range)
+                        .speed(speed),
+                )
+                .changed();
+            if rule.fired {
+                ui.label("(reached)");
+            }
+            // ワーカーが fired を書き換えるので、触ったところだけ戻す
+            if changed {
+                let mut field = field.0.write();
+                let rule = &mut field.pause_rules.rules[k];
+                rule.is_enabled = edited.is_enabled;
+                rule.trigger = edited.trigger;
+            }
+        });
+    }
+    if let Some(notice) = &rules.notice {
+        ui.colored_label(egui::Color32::YELLOW, format!("Paused: {notice}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cells of a hexagonal plate of `radius` around the center of an `n`×`n` grid.
+    fn plate(n: usize, radius: isize) -> Array2<f32> {
+        let center = (n / 2) as isize;
+        Array2::from_shape_fn((n, n), |(i, j)| {
+            let (q, r) = (i as isize - center, j as isize - center);
+            if q.abs() <= radius && r.abs() <= radius && (q + r).abs() <= radius {
+                1.0
+            } else {
+                0.0
+            }
+        })
+    }
+
+    #[test]
+    fn branching_ratio_grows_with_arms() {
+        assert_eq!(branching_ratio(&plate(41, 1)), None);
+        let ratio = branching_ratio(&plate(41, 10)).unwrap();
+        assert!((ratio - 2.0 / 3f32.sqrt()).abs() < 1e-3, "{ratio}");
+        // 板の角から6本の腕を伸ばす
+        let mut cells = plate(41, 10);
+        for (di, dj) in crate::gravner_griffeath::DIRECTIONS {
+            for k in 10..=18 {
+                cells[[(20 + di * k) as usize, (20 + dj * k) as usize]] = 1.0;
+            }
+        }
+        assert!(branching_ratio(&cells).unwrap() > 1.8);
+    }
+
+    #[test]
+    fn rules_fire_once_until_rearmed() {
+        let cells = plate(9, 1);
+        let mut rules = PauseRules::default();
+        assert_eq!(rules.check(0, &cells), None);
+        rules.rules[1].is_enabled = true;
+        rules.rules[1].trigger = Trigger::Mass(5.0);
+        assert!(rules.check(1, &cells).is_some());
+        assert!(rules.notice.as_ref().unwrap().starts_with("Step 1:"));
+        assert_eq!(rules.check(2, &cells), None);
+        rules.rearm();
+        assert!(rules.notice.is_none());
+        assert!(rules.check(3, &cells).is_some());
+    }
+}
@@ -0,0 +1,193 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::BufWriter;
+use std::{
+    fs::OpenOptions,
+    io::{Cursor, Write as _},
+    path::Path,
+};
+
+use bevy::math::Vec2;
+#[cfg(not(target_arch = "wasm32"))]
+use image::ImageBuffer;
+use image::{GrayImage, ImageFormat, Luma};
+use ndarray::Array2;
+
+use crate::{gravner_griffeath::Lattice, visualization::normalize};
+
+/// Width in pixels that the image is scaled up to when no scale is given.
+const DEFAULT_MIN_WIDTH: f32 = 1000.0;
+
+/// Writes the top-down view of the crystal on `lattice` as a grayscale PNG.
+///
+/// `scale` is the number of pixels per cell spacing; when `None`, small grids are
+/// scaled up so that the image is at least [`DEFAULT_MIN_WIDTH`] pixels wide.
+pub fn write_to_png(
+    cells: &Array2<f32>,
+    lattice: Lattice,
+    path: &Path,
+    scale: Option<f32>,
+) -> std::io::Result<()> {
+    let bytes = png_bytes(cells, lattice, scale)?;
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    file.write_all(&bytes)
+}
+
+/// Encodes the image of [`write_to_png`] as the contents of a PNG file.
+pub fn png_bytes(
+    cells: &Array2<f32>,
+    lattice: Lattice,
+    scale: Option<f32>,
+) -> std::io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    rasterize(&normalize(cells), lattice, scale)
+        .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+        .map_err(std::io::Error::other)?;
+    Ok(bytes)
+}
+
+/// Writes the crystal mass as a 16-bit grayscale heightmap PNG.
+///
+/// Black is zero and white the largest mass. `scale` is the number of pixels per cell spacing;
+/// the cells of `lattice` are resampled onto the square pixels, so the crystal keeps its shape.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_heightmap_png(
+    cells: &Array2<f32>,
+    lattice: Lattice,
+    path: &Path,
+    scale: f32,
+) -> std::io::Result<()> {
+    let image = heightmap(cells, lattice, scale);
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    image
+        .write_to(&mut BufWriter::new(file), ImageFormat::Png)
+        .map_err(std::io::Error::other)?;
+    Ok(())
+}
+
+/// Width and height of the image of an `n`×`n` grid on `lattice`, in cell spacings.
+///
+/// The cell `(i, j)` is centered on [`Lattice::position`], with half a spacing around the
+/// outermost cells.
+pub fn extent(n: usize, lattice: Lattice) -> Vec2 {
+    let last = n.max(1) as f32 - 1.0;
+    lattice.position(last, last) + Vec2::ONE
+}
+
+/// Resamples the cells onto square pixels, interpolating linearly within the lattice triangles.
+#[cfg(not(target_arch = "wasm32"))]
+fn heightmap(
+    cells: &Array2<f32>,
+    lattice: Lattice,
+    scale: f32,
+) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+    let max = cells.fold(0.0f32, |a, &b| a.max(b));
+    let Vec2 {
+        x: width,
+        y: height,
+    } = extent(cells.shape()[0], lattice);
+
+    ImageBuffer::from_fn(
+        (width * scale).ceil() as u32,
+        (height * scale).ceil() as u32,
+        |px, py| {
+            let x = (px as f32 + 0.5) / scale - 0.5;
+            let y = height - (py as f32 + 0.5) / scale - 0.5;
+            let value = interpolate(cells, lattice, Vec2::new(x, y));
+            Luma([if max > 0.0 {
+                (value / max * u16::MAX as f32).round() as u16
+            } else {
+                0
+            }])
+        },
+    )
+}
+
+/// Value at the point `p` placed as in [`Lattice::position`], from the three cells of the
+/// lattice triangle containing it, or zero outside the grid.
+///
+/// The square cells are split into triangles along their diagonal.
+pub(crate) fn interpolate(cells: &Array2<f32>, lattice: Lattice, p: Vec2) -> f32 {
+    let n = cells.shape()[0];
+    // 軸座標(q, r)の整数部が三角形の組、小数部が三角形の中の位置
+    let (q, r) = match lattice {
+        Lattice::Hex => {
+            let r = p.y / (3.0f32.sqrt() / 2.0);
+            (p.x - r / 2.0, r)
+        }
+        Lattice::Square4 | Lattice::Square8 => (p.x, p.y),
+    };
+    let (i, j) = (q.floor(), r.floor());
+    let (fq, fr) = (q - i, r - j);
+    let value = |di: usize, dj: usize| {
+        let (ci, cj) = (i as isize + di as isize, j as isize + dj as isize);
+        if (0..n as isize).contains(&ci) && (0..n as isize).contains(&cj) {
+            cells[[ci as usize, cj as usize]]
+        } else {
+            0.0
+        }
+    };
+    if fq + fr <= 1.0 {
+        value(0, 0) * (1.0 - fq - fr) + value(1, 0) * fq + value(0, 1) * fr
+    } else {
+        value(1, 1) * (fq + fr - 1.0) + value(1, 0) * (1.0 - fr) + value(0, 1) * (1.0 - fq)
+    }
+}
+
+/// Draws normalized cell values (see [`normalize`]) as the hexagons or squares of `lattice` on
+/// a grayscale image.
+pub fn rasterize(values: &Array2<f32>, lattice: Lattice, scale: Option<f32>) -> GrayImage {
+    let n = values.shape()[0];
+    let Vec2 {
+        x: width,
+        y: height,
+    } = extent(n, lattice);
+    let scale = scale.unwrap_or_else(|| (DEFAULT_MIN_WIDTH / width).max(1.0));
+
+    GrayImage::from_fn(
+        (width * scale).ceil() as u32,
+        (height * scale).ceil() as u32,
+        |px, py| {
+            // y軸は画像の下から上に向かうようにする
+            let x = (px as f32 + 0.5) / scale - 0.5;
+            let y = height - (py as f32 + 0.5) / scale - 0.5;
+            let Some((i, j)) = lattice.cell_at(Vec2::new(x, y), n) else {
+                return Luma([0]);
+            };
+            // 画面と同じ見た目にする
+            let value = (values[[i, j]] * 254.0) as u8;
+            Luma([if value > 0 { 255 - value } else { 0 }])
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolation_passes_through_the_cells() {
+        let cells = ndarray::array![[0.0, 2.0], [4.0, 6.0]];
+        // セル(i, j)の中心ではその値になる
+        for lattice in Lattice::ALL {
+            for ((i, j), &value) in cells.indexed_iter() {
+                let p = lattice.position(i as f32, j as f32);
+                assert!((interpolate(&cells, lattice, p) - value).abs() < 1e-5);
+            }
+            // 辺の中点では両端の平均
+            let midpoint = Vec2::new(0.5, 0.0);
+            assert!((interpolate(&cells, lattice, midpoint) - 2.0).abs() < 1e-5);
+            assert_eq!(interpolate(&cells, lattice, Vec2::new(-2.0, 0.0)), 0.0);
+        }
+    }
+
+    #[test]
+    fn square_lattices_are_not_sheared() {
+        let mut values = Array2::zeros((5, 5));
+        values[[4, 4]] = 1.0;
+        let image = rasterize(&values, Lattice::Square4, Some(1.0));
+        assert_eq!(image.dimensions(), (5, 5));
+        // 右上の角のセルが右上の画素になる
+        assert_eq!(image.get_pixel(4, 0).0[0], 1);
+        assert_eq!(image.get_pixel(2, 0).0[0], 0);
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::PathBuf,
+};
+
+use bevy::prelude::*;
+
+use crate::gravner_griffeath::SimulationConfigInner;
+
+/// File next to the executable that holds the presets saved by the user.
+const PRESET_FILE: &str = "snowflake-presets.json";
+
+/// Named parameter sets of the Gravner-Griffeath model: the built-in ones followed by the
+/// ones saved by the user.
+#[derive(Resource)]
+pub struct PresetLibrary {
+    pub presets: Vec<(String, SimulationConfigInner)>,
+    /// number of built-in presets at the head of `presets`
+    builtin: usize,
+}
+
+impl PresetLibrary {
+    /// Built-in presets together with the saved ones, if any.
+    pub fn load() -> Self {
+        let mut presets = builtin_presets();
+        let builtin = presets.len();
+        match read_custom_presets() {
+            Ok(custom) => presets.extend(custom),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::error!("Failed to load presets: {e}"),
+        }
+        Self { presets, builtin }
+    }
+
+    /// Saves `config` as a preset named `name`, replacing a saved preset of the same name.
+    pub fn add(&mut self, name: String, config: SimulationConfigInner) -> std::io::Result<PathBuf> {
+        match self.presets[self.builtin..]
+            .iter_mut()
+            .find(|(preset_name, _)| *preset_name == name)
+        {
+            Some((_, preset)) => *preset = config,
+            None => self.presets.push((name, config)),
+        }
+        let path = preset_path()?;
+        let mut writer = BufWriter::new(File::create(&path)?);
+        serde_json::to_writer_pretty(&mut writer, &self.presets[self.builtin..])?;
+        writer.flush()?;
+        Ok(path)
+    }
+}
+
+fn preset_path() -> std::io::Result<PathBuf> {
+    Ok(std::env::current_exe()?.with_file_name(PRESET_FILE))
+}
+
+fn read_custom_presets() -> std::io::Result<Vec<(String, SimulationConfigInner)>> {
+    let reader = BufReader::new(File::open(preset_path()?)?);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+fn builtin_presets() -> Vec<(String, SimulationConfigInner)> {
+    // 参考文献[1]の図に近いパラメータ
+    [
+        ("Fernlike", 0.635, 1.6, 0.4, 0.025, 0.0075, 0.015, 0.00005),
+        (
+            "Stellar dendrite",
+            0.64,
+            1.3,
+            0.08,
+            0.025,
+            0.003,
+            0.07,
+            0.00005,
+        ),
+        ("Plate", 0.38, 1.06, 0.35, 0.112, 0.001, 0.14, 0.0006),
+        ("Needle", 0.4, 2.2, 0.2, 0.05, 0.0005, 0.01, 0.0001),
+    ]
+    .into_iter()
+    .map(|(name, rho, beta, alpha, theta, kappa, mu, gamma)| {
+        (
+            name.to_string(),
+            SimulationConfigInner {
+                rho,
+                beta,
+                alpha,
+                theta,
+                kappa,
+                mu,
+                gamma,
+                ..default()
+            },
+        )
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_round_trip_through_json() {
+        let presets = builtin_presets();
+        let json = serde_json::to_string(&presets).unwrap();
+        // 速度は保存されない。JSONは無限大を表せない
+        assert!(!json.contains("steps_per_second"));
+        let loaded: Vec<(String, SimulationConfigInner)> = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, presets);
+    }
+}
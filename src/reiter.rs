@@ -5,7 +5,9 @@ use bevy_egui::{egui, EguiContexts};
 use ndarray::{Array2, Zip};
 use parking_lot::RwLock;
 
-use crate::{ControlEvent, Field};
+use crate::{
+    crystal_radius, gravner_griffeath::Lattice, ControlEvent, Field, Model, PAUSE_POLL_INTERVAL,
+};
 
 pub struct ReiterSimulatorPlugin;
 
@@ -13,10 +15,14 @@ impl Plugin for ReiterSimulatorPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<SimulationConfig>();
         app.add_systems(Startup, setup);
-        app.add_systems(Update, (configure_ui, event_listener));
+        app.add_systems(Update, (configure_ui, event_listener).run_if(is_active));
     }
 }
 
+fn is_active(field: Res<Field>) -> bool {
+    field.0.read().model == Model::Reiter
+}
+
 #[derive(Resource, Default)]
 struct SimulationConfig(pub Arc<RwLock<SimulationConfigInner>>);
 
@@ -91,25 +97,57 @@ pub fn update_grid(s: &mut Array2<f32>, gamma: f32, alpha: f32) {
 fn setup(config: Res<SimulationConfig>, field: Res<Field>) {
     let field = Arc::clone(&field.0);
     let config = Arc::clone(&config.0);
-    let n = field.read().cells.shape()[0];
-    let mut cells = init_grid(n, config.read().beta);
+    let mut cells = init_grid(field.read().n, config.read().beta);
+
+    let mut initialized_with = None;
 
     std::thread::spawn(move || loop {
+        if field.read().model != Model::Reiter {
+            initialized_with = None;
+            std::thread::sleep(PAUSE_POLL_INTERVAL);
+            continue;
+        }
         let SimulationConfigInner {
             alpha, beta, gamma, ..
         } = *config.read();
 
         if field.read().step == 0 {
-            cells = init_grid(n, beta);
-            field.write().cells = cells.mapv(|x| if x >= 1.0 { x } else { 0.0 });
+            let key = (field.read().n, beta);
+            if initialized_with != Some(key) {
+                cells = init_grid(key.0, beta);
+                let mut field = field.write();
+                if field.model != Model::Reiter {
+                    continue;
+                }
+                // Reiter のモデルは六角格子だけ
+                field.lattice = Lattice::Hex;
+                field.set_cells(cells.mapv(|x| if x >= 1.0 { x } else { 0.0 }));
+                field.radius = 0.0;
+                field.mass = None;
+                initialized_with = Some(key);
+            }
         }
-        if !field.read().is_running {
+        if !field.read().is_running && field.read().pending_steps == 0 {
+            field.write().run_clock.pause();
+            std::thread::sleep(PAUSE_POLL_INTERVAL);
             continue;
         }
+        initialized_with = None;
         let mut field = field.write();
+        if field.model != Model::Reiter {
+            continue;
+        }
+        field.pending_steps = field.pending_steps.saturating_sub(1);
+        field.track_run();
         field.step += 1;
         update_grid(&mut cells, gamma, alpha);
-        field.cells = cells.mapv(|x| if x >= 1.0 { x } else { 0.0 });
+        field.set_cells(cells.mapv(|x| if x >= 1.0 { x } else { 0.0 }));
+        field.radius = crystal_radius(&field.cells);
+        field.record_mass(None);
+        field.check_step_limit();
+        field.check_target_radius();
+        field.check_edge();
+        field.check_pause_rules();
     });
 }
 
@@ -135,8 +173,20 @@ fn configure_ui(mut contexts: EguiContexts, config: Res<SimulationConfig>) {
 
 fn event_listener(field: Res<Field>, mut reset_events: EventReader<ControlEvent>) {
     for event in reset_events.read() {
-        if let ControlEvent::Reset = event {
-            field.0.write().step = 0;
+        match event {
+            ControlEvent::Reset => {
+                field.0.write().step = 0;
+            }
+            ControlEvent::SingleStep => {
+                field.0.write().pending_steps += 1;
+            }
+            // STLの保存はmainで行われる。パラメータのログは記録していない
+            ControlEvent::Save(_) | ControlEvent::SaveAs(_) => {}
+            ControlEvent::SaveState(_) | ControlEvent::LoadState(_) | ControlEvent::SaveNpz(_) => {
+                tracing::warn!("Saving state is not supported by the Reiter model");
+            }
+            // 塗る道具は Gravner-Griffeath のときだけ使える
+            ControlEvent::Paint { .. } => {}
         }
     }
 }
@@ -0,0 +1,197 @@
+//! 2D Fourier spectrum of the crystal, showing its dominant spatial frequencies and the
+//! six-fold structure of their directions.
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use ndarray::{Array2, Axis};
+use rustfft::{num_complex::Complex, FftPlanner};
+
+use crate::{gravner_griffeath::Lattice, png, Field};
+
+/// Sizes of the square the crystal is resampled onto, offered in the window.
+const SIZES: [usize; 3] = [128, 256, 512];
+/// Width of the spectrum image in the window, in points.
+const IMAGE_SIZE: f32 = 256.0;
+
+/// The last spectrum computed, kept until the next "Compute".
+#[derive(Resource)]
+pub struct SpectrumAnalysis {
+    /// side of the resampled square, which is also the number of frequencies on each axis
+    pub size: usize,
+    /// step of the field the spectrum was computed from, and its image
+    spectrum: Option<(u64, egui::TextureHandle)>,
+}
+
+impl Default for SpectrumAnalysis {
+    fn default() -> Self {
+        Self {
+            size: 256,
+            spectrum: None,
+        }
+    }
+}
+
+/// The frozen mass of `cells` resampled onto a `size`×`size` square of pixels.
+///
+/// On the hexagonal lattice the square is the largest one centered on the grid that fits in
+/// it as shown on screen, and the mass is interpolated linearly within the lattice triangles as
+/// in the PNG heightmap. The square lattices are sampled at the nearest cell.
+pub fn resample(cells: &Array2<f32>, lattice: Lattice, size: usize) -> Array2<f32> {
+    let n = cells.shape()[0];
+    let center = (n / 2) as f32;
+    match lattice {
+        Lattice::Hex => {
+            let origin = lattice.position(center, center);
+            // 画面上の高さ n√3/2 に収まる正方形
+            let side = n as f32 * f32::sqrt(3.0) / 2.0;
+            let spacing = side / size as f32;
+            Array2::from_shape_fn((size, size), |(px, py)| {
+                let x = origin.x + (px as f32 + 0.5) * spacing - side / 2.0;
+                let y = origin.y + (py as f32 + 0.5) * spacing - side / 2.0;
+                png::interpolate(cells, lattice, Vec2::new(x, y))
+            })
+        }
+        Lattice::Square4 | Lattice::Square8 => Array2::from_shape_fn((size, size), |(px, py)| {
+            let cell = |p: usize| ((p as f32 + 0.5) * n as f32 / size as f32) as usize;
+            cells[[cell(px).min(n - 1), cell(py).min(n - 1)]]
+        }),
+    }
+}
+
+/// `ln(1 + |F|)` of the 2D discrete Fourier transform of `values`, with the zero frequency
+/// moved to the center.
+pub fn log_magnitude_spectrum(values: &Array2<f32>) -> Array2<f32> {
+    let (rows, columns) = values.dim();
+    let mut data: Array2<Complex<f32>> = values.mapv(|v| Complex::new(v, 0.0));
+    let mut planner = FftPlanner::new();
+    // 各行を変換してから各列を変換する
+    for axis in [Axis(1), Axis(0)] {
+        let fft = planner.plan_fft_forward(data.len_of(axis));
+        for mut lane in data.lanes_mut(axis) {
+            let mut buffer = lane.to_vec();
+            fft.process(&mut buffer);
+            for (value, transformed) in lane.iter_mut().zip(buffer) {
+                *value = transformed;
+            }
+        }
+    }
+    Array2::from_shape_fn((rows, columns), |(i, j)| {
+        // 周波数0が中央に来るよう半分ずらす
+        let k = [
+            (i + rows - rows / 2) % rows,
+            (j + columns - columns / 2) % columns,
+        ];
+        data[k].norm().ln_1p()
+    })
+}
+
+/// Grayscale image of `spectrum` scaled so that its largest value is white, with the first
+/// axis along the width and the second upward.
+fn spectrum_image(spectrum: &Array2<f32>) -> egui::ColorImage {
+    let (width, height) = spectrum.dim();
+    let max = spectrum.fold(0.0f32, |a, &b| a.max(b));
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let value = if max > 0.0 {
+                spectrum[[x, y]] / max
+            } else {
+                0.0
+            };
+            pixels.push((value * 255.0).round() as u8);
+        }
+    }
+    egui::ColorImage::from_gray([width, height], &pixels)
+}
+
+pub fn configure_ui(
+    mut contexts: EguiContexts,
+    field: Res<Field>,
+    mut analysis: ResMut<SpectrumAnalysis>,
+) {
+    egui::Window::new("Spectrum")
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            let analysis = &mut *analysis;
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_label("resolution")
+                    .selected_text(format!("{0}×{0}", analysis.size))
+                    .show_ui(ui, |ui| {
+                        for size in SIZES {
+                            ui.selectable_value(&mut analysis.size, size, format!("{size}×{size}"));
+                        }
+                    });
+                // 大きな格子では重いので、押したときだけ計算する
+                if ui.button("Compute").clicked() {
+                    let (step, square) = {
+                        let field = field.0.read();
+                        (
+                            field.step,
+                            resample(&field.cells, field.lattice, analysis.size),
+                        )
+                    };
+                    let image = spectrum_image(&log_magnitude_spectrum(&square));
+                    let texture =
+                        ui.ctx()
+                            .load_texture("spectrum", image, egui::TextureOptions::NEAREST);
+                    analysis.spectrum = Some((step, texture));
+                }
+            });
+            let Some((step, texture)) = &analysis.spectrum else {
+                ui.label("Press Compute to transform the current crystal");
+                return;
+            };
+            ui.image((texture.id(), egui::Vec2::splat(IMAGE_SIZE)));
+            ui.label(format!(
+                "Step {step}: log magnitude, zero frequency at the center"
+            ))
+            .on_hover_text(
+                "ln(1 + |F|) of the 2D FFT of the frozen mass resampled onto a square. \
+                     Arms show up as six rays at right angles to them, and the spacing of \
+                     side branches as bright spots along the rays.",
+            );
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spectrum_of_a_stripe_pattern_peaks_at_its_frequency() {
+        // 横に4周期の縞
+        let size = 32;
+        let values = Array2::from_shape_fn((size, size), |(i, _)| {
+            (i as f32 * std::f32::consts::TAU * 4.0 / size as f32).cos()
+        });
+        let spectrum = log_magnitude_spectrum(&values);
+        let center = size / 2;
+        // 振幅1の余弦は ±4 の周波数に N²/2 ずつ
+        let expected = ((size * size / 2) as f32).ln_1p();
+        for i in [center + 4, center - 4] {
+            let peak = spectrum[[i, center]];
+            assert!((peak - expected).abs() < 1e-3, "{peak}");
+        }
+        // 他の周波数はほぼ0
+        assert!(spectrum[[center + 3, center]] < 1e-3);
+        assert!(spectrum[[center, center + 4]] < 1e-3);
+    }
+
+    #[test]
+    fn resampling_keeps_the_crystal_centered() {
+        let n = 21;
+        let mut cells = Array2::zeros((n, n));
+        cells[[10, 10]] = 1.0;
+        for lattice in Lattice::ALL {
+            let square = resample(&cells, lattice, 64);
+            let ((i, j), _) = square
+                .indexed_iter()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .unwrap();
+            assert!(
+                i.abs_diff(32) <= 2 && j.abs_diff(32) <= 2,
+                "{lattice:?}: {i} {j}"
+            );
+        }
+    }
+}
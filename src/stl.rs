@@ -1,12 +1,240 @@
-use std::{fs::OpenOptions, path::PathBuf};
+use std::{fs::OpenOptions, io::Write, path::Path};
 
 use bevy::math::Vec3;
-use chrono::{DateTime, Local};
-use fnv::{FnvHashMap, FnvHashSet};
-use ndarray::Array2;
+use ndarray::{Array2, Zip};
 use stl_io::{Normal, Triangle, Vertex};
 
-use crate::Field;
+use crate::{
+    contour::extract_contours,
+    gravner_griffeath::{Lattice, DIRECTIONS},
+};
+
+/// Default horizontal size of a cell spacing in the exported mesh.
+pub const XY_SCALE: f32 = 0.025;
+/// Default height of the mesh per unit of crystal mass.
+pub const Z_SCALE: f32 = 0.1;
+
+/// Encoding of the STL file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StlFormat {
+    #[default]
+    Binary,
+    /// human-readable, for older slicers and diffing
+    Ascii,
+}
+
+/// Shape of the exported STL.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StlGeometry {
+    /// filled relief of the crystal
+    #[default]
+    Solid,
+    /// ribbons of the given width, in cell spacings, along the outlines of the crystal
+    Outline { width: f32 },
+    /// the relief of `Solid` around a closed cavity, with walls of the given thickness in cell
+    /// spacings
+    Hollow { wall: f32 },
+}
+
+/// Everything about the exported STL but the cells.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StlOptions {
+    pub format: StlFormat,
+    /// arrangement of the cells
+    pub lattice: Lattice,
+    /// horizontal size of a cell spacing
+    pub xy_scale: f32,
+    /// height per unit of crystal mass
+    pub z_scale: f32,
+    pub relief: Relief,
+    pub geometry: StlGeometry,
+    /// export only the 60° wedge of [`wedge`]
+    pub wedge: bool,
+}
+
+impl Default for StlOptions {
+    fn default() -> Self {
+        Self {
+            format: StlFormat::default(),
+            lattice: Lattice::default(),
+            xy_scale: XY_SCALE,
+            z_scale: Z_SCALE,
+            relief: Relief::default(),
+            geometry: StlGeometry::default(),
+            wedge: false,
+        }
+    }
+}
+
+/// Mapping from the crystal mass of a cell to the height of the mesh, before `z_scale`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HeightMapping {
+    #[default]
+    Linear,
+    /// flattens the tall center
+    Sqrt,
+    /// `ln(1 + mass)`, flatter than `Sqrt`
+    Log,
+    /// linear up to the given mass, flat above it
+    Clamped(f32),
+}
+
+impl HeightMapping {
+    pub fn label(&self) -> &'static str {
+        match self {
+            HeightMapping::Linear => "linear",
+            HeightMapping::Sqrt => "sqrt",
+            HeightMapping::Log => "log",
+            HeightMapping::Clamped(_) => "clamped",
+        }
+    }
+
+    pub fn height(&self, mass: f32) -> f32 {
+        match *self {
+            HeightMapping::Linear => mass,
+            HeightMapping::Sqrt => mass.sqrt(),
+            HeightMapping::Log => mass.ln_1p(),
+            HeightMapping::Clamped(max) => mass.min(max),
+        }
+    }
+}
+
+/// How the crystal mass becomes the height, and the footprint, of the exported relief.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Relief {
+    pub mapping: HeightMapping,
+    /// snap the heights to this many terraces, see [`cells_to_triangles`]
+    pub terrace_levels: Option<u32>,
+    /// rounds of smoothing of the heights, see [`cells_to_triangles`]
+    pub smoothing: u32,
+    /// widen the arms thinner than this many cell spacings, see [`thicken`]
+    pub min_feature: Option<f32>,
+}
+
+/// The cells of the sector from the +i axis to the +j axis around `(n / 2, n / 2)`, with the
+/// rest emptied; 60° on the hexagonal lattice and 90° on the square ones.
+///
+/// The sector is bounded by two rows of the lattice through the center, which both belong to
+/// it, so the mesh of the wedge is cut by flat walls along them. Six copies rotated by 60°
+/// about the center, or mirrored across the walls for a symmetric crystal, rebuild the flake.
+pub fn wedge(cells: &Array2<f32>) -> Array2<f32> {
+    let center = cells.shape()[0] / 2;
+    Array2::from_shape_fn(cells.dim(), |(i, j)| {
+        if i >= center && j >= center {
+            cells[[i, j]]
+        } else {
+            0.0
+        }
+    })
+}
+
+/// The cells of the exported mesh: thickened as in `relief`, then cut to the [`wedge`] if
+/// `is_wedge`, with the thickening taken out of the returned relief.
+///
+/// Thickening the wedge itself would widen the arms cut in half along its walls past them.
+pub fn export_cells(cells: &Array2<f32>, relief: Relief, is_wedge: bool) -> (Array2<f32>, Relief) {
+    if !is_wedge {
+        return (cells.clone(), relief);
+    }
+    let cells = match relief.min_feature {
+        Some(min_feature) => wedge(&thicken(cells, min_feature)),
+        None => wedge(cells),
+    };
+    let relief = Relief {
+        min_feature: None,
+        ..relief
+    };
+    (cells, relief)
+}
+
+/// `cells` with the parts thinner than `min_feature` cell spacings widened to at least that,
+/// for printers whose nozzle cannot draw the finest dendrite tips.
+///
+/// The thin parts are the frozen cells that an opening of the `cells > 0` mask by hexagonal
+/// steps removes. They grow by the same steps, each new cell taking the mass of the cells it
+/// grows from, while the thicker parts keep their outline.
+pub fn thicken(cells: &Array2<f32>, min_feature: f32) -> Array2<f32> {
+    // 1セル幅の腕は 1 + 2 * steps セル幅になる
+    let steps = ((min_feature - 1.0) / 2.0).ceil().max(0.0) as usize;
+    if steps == 0 {
+        return cells.clone();
+    }
+    let core = erode(&cells.mapv(|c| c > 0.0), steps);
+    let opened = dilate(&core.mapv(|c| if c { 1.0 } else { 0.0 }), steps);
+    let thin = Zip::from(cells)
+        .and(&opened)
+        .map_collect(|&c, &o| if o > 0.0 { 0.0 } else { c });
+    let grown = dilate(&thin, steps);
+    Zip::from(cells)
+        .and(&grown)
+        .map_collect(|&c, &g| if c > 0.0 { c } else { g })
+}
+
+/// `values` grown by `steps` hexagonal steps, each empty cell next to a positive one taking the
+/// largest value among its neighbors.
+fn dilate(values: &Array2<f32>, steps: usize) -> Array2<f32> {
+    let n = values.shape()[0] as isize;
+    let mut dilated = values.clone();
+    for _ in 0..steps {
+        dilated = Array2::from_shape_fn(dilated.dim(), |(i, j)| {
+            if dilated[[i, j]] > 0.0 {
+                return dilated[[i, j]];
+            }
+            DIRECTIONS
+                .iter()
+                .map(|&(di, dj)| (i as isize + di, j as isize + dj))
+                .filter(|&(i, j)| (0..n).contains(&i) && (0..n).contains(&j))
+                .map(|(i, j)| dilated[[i as usize, j as usize]])
+                .fold(dilated[[i, j]], f32::max)
+        });
+    }
+    dilated
+}
+
+/// Heights of the cells for the relief: the mapped mass times `z_scale`, smoothed and then
+/// snapped to the terraces of `relief`.
+fn relief_heights(cells: &Array2<f32>, z_scale: f32, relief: Relief) -> Array2<f32> {
+    let mut heights = cells.mapv(|mass| relief.mapping.height(mass) * z_scale);
+    for _ in 0..relief.smoothing {
+        heights = smooth(&heights);
+    }
+    let max = heights.fold(0.0f32, |a, &b| a.max(b));
+    if let Some(levels) = relief
+        .terrace_levels
+        .filter(|&levels| levels > 0 && max > 0.0)
+    {
+        let step = max / levels as f32;
+        // 凍ったセルは一番下の段より低くしない
+        heights.mapv_inplace(|h| {
+            if h > 0.0 {
+                (h / step).round().clamp(1.0, levels as f32) * step
+            } else {
+                h
+            }
+        });
+    }
+    heights
+}
+
+/// One round of Laplacian smoothing: each frozen cell takes the mean height of itself and its
+/// frozen neighbors on the mesh. Empty cells stay at zero and are left out of the means, so
+/// the outline keeps its place and the border is not dragged down to the background.
+fn smooth(heights: &Array2<f32>) -> Array2<f32> {
+    let n = heights.shape()[0] as isize;
+    Array2::from_shape_fn(heights.dim(), |(i, j)| {
+        if heights[[i, j]] <= 0.0 {
+            return heights[[i, j]];
+        }
+        let (sum, count) = DIRECTIONS
+            .iter()
+            .map(|&(di, dj)| (i as isize + di, j as isize + dj))
+            .filter(|&(i, j)| (0..n).contains(&i) && (0..n).contains(&j))
+            .map(|(i, j)| heights[[i as usize, j as usize]])
+            .filter(|&h| h > 0.0)
+            .fold((heights[[i, j]], 1), |(sum, count), h| (sum + h, count + 1));
+        sum / count as f32
+    })
+}
 
 #[derive(Clone, Copy)]
 struct Facet(Vec3, Vec3, Vec3);
@@ -31,29 +259,180 @@ impl Facet {
     }
 }
 
-pub fn write_to_stl(field: &Field, now: DateTime<Local>) -> std::io::Result<PathBuf> {
-    let triangles = cells_to_triangles(&field.0.read().cells, 0.025, 0.1);
-    let filename = format!("snowflake-{}.stl", now.format("%Y%m%d%H%M%S"));
-    let path = PathBuf::from(&filename);
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(&path)?;
-    stl_io::write_stl(&mut file, triangles.iter())?;
-    Ok(path)
+/// Writes the mesh of the cells to a new file at `path`, see [`stl_bytes`].
+pub fn write_to_stl(
+    cells: &Array2<f32>,
+    path: &Path,
+    options: StlOptions,
+    progress: &dyn Fn(f32),
+) -> std::io::Result<()> {
+    let bytes = stl_bytes(cells, options, progress)?;
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    file.write_all(&bytes)
+}
+
+/// Encodes the mesh of the cells as the contents of an STL file.
+///
+/// `progress` is called with the fraction of the mesh built so far, from 0 to 1.
+pub fn stl_bytes(
+    cells: &Array2<f32>,
+    options: StlOptions,
+    progress: &dyn Fn(f32),
+) -> std::io::Result<Vec<u8>> {
+    let StlOptions {
+        format,
+        lattice,
+        xy_scale,
+        z_scale,
+        relief,
+        geometry,
+        wedge: is_wedge,
+    } = options;
+    let (cells, relief) = export_cells(cells, relief, is_wedge);
+    let cells = &cells;
+    let triangles = match geometry {
+        StlGeometry::Solid => {
+            cells_to_triangles(cells, lattice, xy_scale, z_scale, relief, progress)
+        }
+        StlGeometry::Outline { width } => {
+            cells_to_outline_triangles(cells, lattice, xy_scale, z_scale, relief, width, progress)
+        }
+        StlGeometry::Hollow { wall } => {
+            cells_to_hollow_triangles(cells, lattice, xy_scale, z_scale, relief, wall, progress)
+        }
+    };
+    let mut bytes = Vec::new();
+    match format {
+        StlFormat::Binary => stl_io::write_stl(&mut bytes, triangles.iter())?,
+        StlFormat::Ascii => write_ascii_stl(&mut bytes, &triangles)?,
+    }
+    Ok(bytes)
+}
+
+fn write_ascii_stl(writer: &mut impl Write, triangles: &[Triangle]) -> std::io::Result<()> {
+    writeln!(writer, "solid snowflake")?;
+    for triangle in triangles {
+        let n = triangle.normal;
+        writeln!(writer, "  facet normal {} {} {}", n[0], n[1], n[2])?;
+        writeln!(writer, "    outer loop")?;
+        for v in &triangle.vertices {
+            writeln!(writer, "      vertex {} {} {}", v[0], v[1], v[2])?;
+        }
+        writeln!(writer, "    endloop")?;
+        writeln!(writer, "  endfacet")?;
+    }
+    writeln!(writer, "endsolid snowflake")?;
+    writer.flush()
+}
+
+/// Share of the progress of [`cells_to_triangles`] taken by the rows of the top surface.
+const ROWS_SHARE: f32 = 0.9;
+
+/// Builds a closed relief of the crystal on `lattice`, mirrored below the xy plane.
+///
+/// The height of a cell is its mass through the mapping of `relief` times `z_scale`. Each round
+/// of `smoothing` averages the height of every frozen cell with its frozen neighbors, which
+/// softens the stair steps while the outline stays put. With `terrace_levels`, each height is
+/// then snapped to the nearest of that many evenly spaced levels up to the highest cell, for a
+/// stepped, contour-map look. With `min_feature`, the arms thinner than that are widened by
+/// [`thicken`] first, so that they can be printed.
+///
+/// `progress` is called after each row of cells with the fraction done so far, the rows making
+/// up [`ROWS_SHARE`] of the work and the side walls along the contours the rest.
+pub fn cells_to_triangles(
+    cells: &Array2<f32>,
+    lattice: Lattice,
+    xy_scale: f32,
+    z_scale: f32,
+    relief: Relief,
+    progress: &dyn Fn(f32),
+) -> Vec<Triangle> {
+    let thickened = relief
+        .min_feature
+        .map(|min_feature| thicken(cells, min_feature));
+    let cells = thickened.as_ref().unwrap_or(cells);
+    let heights = relief_heights(cells, z_scale, relief);
+    relief_facets(cells, &heights, lattice, xy_scale, progress)
+        .into_iter()
+        .map(Facet::to_triangle)
+        .collect()
+}
+
+/// Builds the relief of [`cells_to_triangles`] with a closed cavity inside, for lighter prints.
+///
+/// The cavity is the same relief lowered by `wall` cell spacings, over the cells at least
+/// `wall` steps, rounded up, inside the crystal. Its surface faces inward, so
+/// the mesh stays watertight as two nested shells. Parts of the crystal too thin or too low
+/// for the walls stay solid.
+pub fn cells_to_hollow_triangles(
+    cells: &Array2<f32>,
+    lattice: Lattice,
+    xy_scale: f32,
+    z_scale: f32,
+    relief: Relief,
+    wall: f32,
+    progress: &dyn Fn(f32),
+) -> Vec<Triangle> {
+    let thickened = relief
+        .min_feature
+        .map(|min_feature| thicken(cells, min_feature));
+    let cells = thickened.as_ref().unwrap_or(cells);
+    let heights = relief_heights(cells, z_scale, relief);
+    let mut facets = relief_facets(cells, &heights, lattice, xy_scale, &|f| progress(f / 2.0));
+    let inside = erode(&cells.mapv(|c| c > 0.0), wall.ceil().max(1.0) as usize);
+    let thickness = wall * xy_scale;
+    let cavity = Array2::from_shape_fn(cells.dim(), |(i, j)| {
+        if inside[[i, j]] {
+            (heights[[i, j]] - thickness).max(0.0)
+        } else {
+            0.0
+        }
+    });
+    // 空洞の面は内側を向くよう裏返す
+    facets.extend(
+        relief_facets(&cavity, &cavity, lattice, xy_scale, &|f| {
+            progress(0.5 + f / 2.0)
+        })
+        .into_iter()
+        .map(|Facet(p0, p1, p2)| Facet(p2, p1, p0)),
+    );
+    facets.into_iter().map(Facet::to_triangle).collect()
+}
+
+/// Cells of `mask` whose neighbors within `steps` steps are all in `mask`, with the outside of
+/// the grid counted as empty.
+///
+/// The neighbors are those of the mesh triangles, the six of the hexagonal lattice whatever
+/// the lattice, so that no cell left touches the outline.
+fn erode(mask: &Array2<bool>, steps: usize) -> Array2<bool> {
+    let n = mask.shape()[0] as isize;
+    let mut eroded = mask.clone();
+    for _ in 0..steps {
+        eroded = Array2::from_shape_fn(eroded.dim(), |(i, j)| {
+            eroded[[i, j]]
+                && DIRECTIONS.iter().all(|&(di, dj)| {
+                    let (ni, nj) = (i as isize + di, j as isize + dj);
+                    (0..n).contains(&ni)
+                        && (0..n).contains(&nj)
+                        && eroded[[ni as usize, nj as usize]]
+                })
+        });
+    }
+    eroded
 }
 
-fn cells_to_triangles(cells: &Array2<f32>, xy_scale: f32, z_scale: f32) -> Vec<Triangle> {
+/// Top and mirrored bottom surfaces over the cells with positive `cells`, at `heights`, closed
+/// by side walls along their contours.
+fn relief_facets(
+    cells: &Array2<f32>,
+    heights: &Array2<f32>,
+    lattice: Lattice,
+    xy_scale: f32,
+    progress: &dyn Fn(f32),
+) -> Vec<Facet> {
     let n = cells.shape()[0];
-    let sqrt3_2 = 3.0f32.sqrt() / 2.0;
-    let x_offset = (n as f32 - 1.0) * 1.5 * xy_scale / 2.0;
-    let y_offset = (n as f32 - 1.0) * xy_scale * sqrt3_2 / 2.0;
+    let point = vertex(lattice, n, xy_scale, heights);
     let mut facets = Vec::new();
-    // 辺の集合。始点をキーとして終点の集合を値とする。
-    let mut segments: FnvHashMap<(usize, usize), FnvHashSet<(usize, usize)>> =
-        FnvHashMap::default();
-    let directions0 = [(0, 0), (1, 0), (0, 1)];
-    let directions1 = [(1, 0), (1, 1), (0, 1)];
 
     for i in 0..(n - 1) {
         for j in 0..(n - 1) {
@@ -68,26 +447,10 @@ fn cells_to_triangles(cells: &Array2<f32>, xy_scale: f32, z_scale: f32) -> Vec<T
                ↑     ↑
              (i, j) (i1, j)
             */
-            let p00 = Vec3::new(
-                (i as f32 + j as f32 * 0.5) * xy_scale - x_offset,
-                j as f32 * sqrt3_2 * xy_scale - y_offset,
-                cells[[i, j]] * z_scale,
-            );
-            let p01 = Vec3::new(
-                (i1 as f32 + j as f32 * 0.5) * xy_scale - x_offset,
-                j as f32 * sqrt3_2 * xy_scale - y_offset,
-                cells[[i1, j]] * z_scale,
-            );
-            let p10 = Vec3::new(
-                (i as f32 + j1 as f32 * 0.5) * xy_scale - x_offset,
-                j1 as f32 * sqrt3_2 * xy_scale - y_offset,
-                cells[[i, j1]] * z_scale,
-            );
-            let p11 = Vec3::new(
-                (i1 as f32 + j1 as f32 * 0.5) * xy_scale - x_offset,
-                j1 as f32 * sqrt3_2 * xy_scale - y_offset,
-                cells[[i1, j1]] * z_scale,
-            );
+            let p00 = point(i, j);
+            let p01 = point(i1, j);
+            let p10 = point(i, j1);
+            let p11 = point(i1, j1);
 
             // (p00, p01, p10)とその裏
             if cells[[i, j]] > 0.0 && cells[[i1, j]] > 0.0 && cells[[i, j1]] > 0.0 {
@@ -109,88 +472,442 @@ fn cells_to_triangles(cells: &Array2<f32>, xy_scale: f32, z_scale: f32) -> Vec<T
                     p01.with_z(-p01.z),
                 ));
             }
+        }
+        progress((i + 1) as f32 / (n - 1) as f32 * ROWS_SHARE);
+    }
 
-            // 輪郭抽出のために辺を追加
-            if cells[[i, j]] > 0.0 && cells[[i1, j]] > 0.0 && cells[[i, j1]] > 0.0 {
-                for k in 0..3 {
-                    let start = (i + directions0[k].0, j + directions0[k].1);
-                    let end = (
-                        i + directions0[(k + 1) % 3].0,
-                        j + directions0[(k + 1) % 3].1,
-                    );
-                    // 逆向きの辺がすでに追加されていたら打ち消して終了、そうでなければ追加
-                    if let Some(end_set) = segments.get_mut(&end) {
-                        if !end_set.remove(&start) {
-                            segments.entry(start).or_default().insert(end);
-                        } else if end_set.is_empty() {
-                            segments.remove(&end);
-                        }
-                    } else {
-                        segments.entry(start).or_default().insert(end);
-                    }
+    // 輪郭から側面を生成
+    for (p00, p01) in contour_edges(cells, heights, lattice, xy_scale) {
+        // 側面の四角形 p00, p01, q01, q00 を対角線 p00-q01 で2つの三角形に分ける。
+        // 輪郭は上面の三角形と同じ向きに回っているので、上面とは逆向きに辺 p01 → p00 を使う
+        let q00 = p00.with_z(-p00.z);
+        let q01 = p01.with_z(-p01.z);
+        facets.push(Facet(p01, p00, q01));
+        facets.push(Facet(q00, q01, p00));
+    }
+    progress(1.0);
+
+    facets
+}
+
+/// Builds ribbons of `width` cell spacings along the outlines of the crystal.
+///
+/// Each outline edge becomes a box between the relief heights of [`cells_to_triangles`] and
+/// their mirror images, extended by half the width at both ends so that neighboring boxes
+/// overlap at the corners. The boxes are closed but not merged, which slicers accept.
+/// `progress` is called with the fraction done, most of which is tracing the contours.
+pub fn cells_to_outline_triangles(
+    cells: &Array2<f32>,
+    lattice: Lattice,
+    xy_scale: f32,
+    z_scale: f32,
+    relief: Relief,
+    width: f32,
+    progress: &dyn Fn(f32),
+) -> Vec<Triangle> {
+    let thickened = relief
+        .min_feature
+        .map(|min_feature| thicken(cells, min_feature));
+    let cells = thickened.as_ref().unwrap_or(cells);
+    let heights = relief_heights(cells, z_scale, relief);
+    let half_width = width * xy_scale / 2.0;
+    let mut facets = Vec::new();
+
+    let edges = contour_edges(cells, &heights, lattice, xy_scale);
+    progress(ROWS_SHARE);
+    for (p0, p1) in edges {
+        let along = (p1 - p0).with_z(0.0).normalize() * half_width;
+        // 辺の左手側。輪郭は結晶を左に見て回る
+        let left = Vec3::new(-along.y, along.x, 0.0);
+        let (a, b) = (p0 - along, p1 + along);
+        let (a_l, a_r, b_l, b_r) = (a + left, a - left, b + left, b - left);
+        let mirror = |p: Vec3| p.with_z(-p.z);
+        let (a_l_, a_r_, b_l_, b_r_) = (mirror(a_l), mirror(a_r), mirror(b_l), mirror(b_r));
+        // 上面、下面、右側面、左側面、始点側の端、終点側の端の順。どれも外から見て反時計回り
+        for [q0, q1, q2, q3] in [
+            [a_r, b_r, b_l, a_l],
+            [a_l_, b_l_, b_r_, a_r_],
+            [a_r_, b_r_, b_r, a_r],
+            [b_l_, a_l_, a_l, b_l],
+            [a_l_, a_r_, a_r, a_l],
+            [b_r_, b_l_, b_l, b_r],
+        ] {
+            facets.push(Facet(q0, q1, q2));
+            facets.push(Facet(q0, q2, q3));
+        }
+    }
+    progress(1.0);
+
+    facets.into_iter().map(Facet::to_triangle).collect()
+}
+
+/// Top vertex of the cell `(i, j)` at its height in `heights`, with the grid centered on the
+/// origin.
+fn vertex(
+    lattice: Lattice,
+    n: usize,
+    xy_scale: f32,
+    heights: &Array2<f32>,
+) -> impl Fn(usize, usize) -> Vec3 + '_ {
+    let half = (n as f32 - 1.0) / 2.0;
+    let offset = lattice.position(half, half);
+    move |i, j| {
+        let p = (lattice.position(i as f32, j as f32) - offset) * xy_scale;
+        p.extend(heights[[i, j]])
+    }
+}
+
+/// Top end points of the outline edges of the crystal, following [`extract_contours`].
+fn contour_edges(
+    cells: &Array2<f32>,
+    heights: &Array2<f32>,
+    lattice: Lattice,
+    xy_scale: f32,
+) -> Vec<(Vec3, Vec3)> {
+    let vertex = vertex(lattice, cells.shape()[0], xy_scale, heights);
+    let point = |(i, j): (usize, usize)| vertex(i, j);
+    extract_contours(&cells.mapv(|c| c > 0.0))
+        .into_iter()
+        .flat_map(|contour| {
+            contour
+                .windows(2)
+                .map(|edge| (point(edge[0]), point(edge[1])))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use fnv::FnvHashMap;
+    use ndarray::Array2;
+
+    use super::*;
+
+    /// Counts the edges that are not shared by exactly one pair of
+    /// oppositely oriented triangles.
+    fn count_open_edges(triangles: &[Triangle]) -> usize {
+        let key = |v: &Vertex| [v[0].to_bits(), v[1].to_bits(), v[2].to_bits()];
+        let mut edges: FnvHashMap<([u32; 3], [u32; 3]), i32> = FnvHashMap::default();
+        for triangle in triangles {
+            for k in 0..3 {
+                let a = key(&triangle.vertices[k]);
+                let b = key(&triangle.vertices[(k + 1) % 3]);
+                // 向きを区別して数え、逆向きの辺と打ち消し合うようにする
+                if a < b {
+                    *edges.entry((a, b)).or_default() += 1;
+                } else {
+                    *edges.entry((b, a)).or_default() -= 1;
                 }
             }
-            if cells[[i1, j]] > 0.0 && cells[[i1, j1]] > 0.0 && cells[[i, j1]] > 0.0 {
-                for k in 0..3 {
-                    let start = (i + directions1[k].0, j + directions1[k].1);
-                    let end = (
-                        i + directions1[(k + 1) % 3].0,
-                        j + directions1[(k + 1) % 3].1,
-                    );
-                    if let Some(end_set) = segments.get_mut(&end) {
-                        if !end_set.remove(&start) {
-                            segments.entry(start).or_default().insert(end);
-                        } else if end_set.is_empty() {
-                            segments.remove(&end);
-                        }
-                    } else {
-                        segments.entry(start).or_default().insert(end);
-                    }
-                }
+        }
+        edges.values().filter(|&&count| count != 0).count()
+    }
+
+    fn hexagon(n: usize, radius: isize) -> Array2<f32> {
+        let c = (n / 2) as isize;
+        Array2::from_shape_fn((n, n), |(i, j)| {
+            let (q, r) = (i as isize - c, j as isize - c);
+            if q.abs().max(r.abs()).max((q + r).abs()) <= radius {
+                1.0 + (q + r) as f32 * 0.1
+            } else {
+                0.0
+            }
+        })
+    }
+
+    #[test]
+    fn hexagon_is_watertight() {
+        let triangles = cells_to_triangles(
+            &hexagon(9, 2),
+            Lattice::Hex,
+            XY_SCALE,
+            Z_SCALE,
+            Relief::default(),
+            &|_| {},
+        );
+        assert!(!triangles.is_empty());
+        assert_eq!(count_open_edges(&triangles), 0);
+    }
+
+    #[test]
+    fn square_lattice_lays_the_cells_on_squares() {
+        let cells = hexagon(9, 2);
+        let triangles = cells_to_triangles(
+            &cells,
+            Lattice::Square4,
+            1.0,
+            1.0,
+            Relief::default(),
+            &|_| {},
+        );
+        assert_eq!(count_open_edges(&triangles), 0);
+        // 斜交しないので、i の範囲 2..=6 がそのまま x の幅になる
+        let xs = triangles
+            .iter()
+            .flat_map(|triangle| triangle.vertices)
+            .map(|vertex| vertex[0]);
+        let (min, max) = xs.fold((f32::MAX, f32::MIN), |(a, b), x| (a.min(x), b.max(x)));
+        assert!(
+            (min + 2.0).abs() < 1e-5 && (max - 2.0).abs() < 1e-5,
+            "{min} {max}"
+        );
+    }
+
+    #[test]
+    fn height_mappings_stay_watertight() {
+        for mapping in [
+            HeightMapping::Sqrt,
+            HeightMapping::Log,
+            HeightMapping::Clamped(1.05),
+        ] {
+            let triangles = cells_to_triangles(
+                &hexagon(9, 2),
+                Lattice::Hex,
+                XY_SCALE,
+                Z_SCALE,
+                Relief {
+                    mapping,
+                    ..Default::default()
+                },
+                &|_| {},
+            );
+            assert_eq!(count_open_edges(&triangles), 0, "{mapping:?}");
+            let top = triangles
+                .iter()
+                .flat_map(|triangle| triangle.vertices)
+                .map(|vertex| vertex[2])
+                .fold(0.0f32, f32::max);
+            assert!(top <= mapping.height(1.2) * Z_SCALE + 1e-6, "{mapping:?}");
+        }
+    }
+
+    #[test]
+    fn terraces_snap_heights_to_levels() {
+        // 質量は 0.8 から 1.2 まで
+        let triangles = cells_to_triangles(
+            &hexagon(9, 2),
+            Lattice::Hex,
+            1.0,
+            1.0,
+            Relief {
+                terrace_levels: Some(4),
+                ..Default::default()
+            },
+            &|_| {},
+        );
+        assert_eq!(count_open_edges(&triangles), 0);
+        for vertex in triangles.iter().flat_map(|triangle| triangle.vertices) {
+            let level = vertex[2].abs() / (1.2 / 4.0);
+            assert!((level - level.round()).abs() < 1e-4, "{}", vertex[2]);
+            // 凍ったセルは平らにならない
+            assert!(level.round() >= 1.0, "{}", vertex[2]);
+        }
+    }
+
+    #[test]
+    fn thickening_widens_only_the_thin_arms() {
+        let mut cells = hexagon(21, 3);
+        // 中心から +i 方向へ1セル幅の腕を伸ばす
+        for i in 14..19 {
+            cells[[i, 10]] = 0.5;
+        }
+        assert_eq!(thicken(&cells, 1.0), cells);
+        let thickened = thicken(&cells, 3.0);
+        assert_eq!(thickened[[16, 9]], 0.5);
+        assert_eq!(thickened[[16, 11]], 0.5);
+        // 太い部分の輪郭はそのまま
+        assert_eq!(thickened[[10, 14]], 0.0);
+        for ((i, j), &mass) in cells.indexed_iter() {
+            if mass > 0.0 {
+                assert_eq!(thickened[[i, j]], mass);
             }
         }
+        let relief = Relief {
+            min_feature: Some(3.0),
+            ..Default::default()
+        };
+        let triangles = cells_to_triangles(&cells, Lattice::Hex, 1.0, 1.0, relief, &|_| {});
+        assert_eq!(count_open_edges(&triangles), 0);
+
+        // 楔は太らせてから切り出すので壁の外にはみ出さない
+        let (wedged, relief) = export_cells(&cells, relief, true);
+        assert_eq!(relief.min_feature, None);
+        assert_eq!(wedged[[16, 11]], 0.5);
+        assert_eq!(wedged[[16, 9]], 0.0);
     }
 
-    // 輪郭抽出
-    let mut contours = Vec::new();
-    while !segments.is_empty() {
-        let start = *segments.keys().next().unwrap();
-        let mut contour = vec![start];
-        let mut current = start;
-        while let Some(next_set) = segments.get_mut(&current) {
-            if next_set.len() != 1 {
-                tracing::warn!("Should be 1");
+    #[test]
+    fn smoothing_flattens_spikes_but_not_the_border() {
+        let mut cells = hexagon(9, 2);
+        cells[[4, 4]] = 3.0;
+        let relief = Relief {
+            smoothing: 3,
+            ..Default::default()
+        };
+        let heights = relief_heights(&cells, 1.0, relief);
+        assert!(heights[[4, 4]] < 2.0, "{}", heights[[4, 4]]);
+        for ((i, j), &mass) in cells.indexed_iter() {
+            if mass > 0.0 {
+                // 縁のセルも背景の 0 に引っ張られない
+                assert!(heights[[i, j]] >= 0.8 - 1e-6, "{i} {j} {}", heights[[i, j]]);
+            } else {
+                assert_eq!(heights[[i, j]], 0.0);
             }
-            let next = *next_set.iter().next().unwrap();
-            contour.push(next);
-            segments.remove(&current);
-            current = next;
         }
-        contours.push(contour);
+        let triangles = cells_to_triangles(&cells, Lattice::Hex, 1.0, 1.0, relief, &|_| {});
+        assert_eq!(count_open_edges(&triangles), 0);
     }
 
-    // 輪郭から側面を生成
-    for contour in contours {
-        for i in 0..(contour.len() - 1) {
-            let c0x = contour[i].0 as f32 + contour[i].1 as f32 * 0.5;
-            let c0y = contour[i].1 as f32 * sqrt3_2;
-            let c1x = contour[i + 1].0 as f32 + contour[i + 1].1 as f32 * 0.5;
-            let c1y = contour[i + 1].1 as f32 * sqrt3_2;
-            let p00 = Vec3::new(
-                c0x * xy_scale - x_offset,
-                c0y * xy_scale - y_offset,
-                cells[[contour[i].0, contour[i].1]] * z_scale,
-            );
-            let p01 = Vec3::new(
-                c1x * xy_scale - x_offset,
-                c1y * xy_scale - y_offset,
-                cells[[contour[i + 1].0, contour[i + 1].1]] * z_scale,
+    #[test]
+    fn progress_rises_to_one() {
+        let reported = std::cell::RefCell::new(Vec::new());
+        cells_to_triangles(
+            &hexagon(9, 2),
+            Lattice::Hex,
+            XY_SCALE,
+            Z_SCALE,
+            Relief::default(),
+            &|f| reported.borrow_mut().push(f),
+        );
+        let reported = reported.into_inner();
+        // 8行と側面の分
+        assert_eq!(reported.len(), 8 + 1);
+        assert!(reported.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(reported.last(), Some(&1.0));
+    }
+
+    #[test]
+    fn wedge_is_a_closed_sixth() {
+        let cells = hexagon(9, 2);
+        let triangles = cells_to_triangles(
+            &wedge(&cells),
+            Lattice::Hex,
+            1.0,
+            1.0,
+            Relief::default(),
+            &|_| {},
+        );
+        assert_eq!(count_open_edges(&triangles), 0);
+        // 中心のセルは原点に来るので、原点から60°の扇形の中に収まる
+        for vertex in triangles.iter().flat_map(|triangle| triangle.vertices) {
+            let p = Vec3::new(vertex[0], vertex[1], 0.0);
+            let angle = p.y.atan2(p.x).to_degrees();
+            assert!(
+                p.length() < 1e-5 || (-1e-3..=60.0 + 1e-3).contains(&angle),
+                "{p}"
             );
-            facets.push(Facet(p01, p00, p01.with_z(-p01.z)));
-            facets.push(Facet(p00.with_z(-p00.z), p01.with_z(-p01.z), p00));
         }
+        // 上面の三角形は元の6分の1
+        let whole = cells_to_triangles(&cells, Lattice::Hex, 1.0, 1.0, Relief::default(), &|_| {});
+        let top = |triangles: &[Triangle]| {
+            triangles
+                .iter()
+                .filter(|t| t.vertices.iter().all(|v| v[2] > 0.0) && t.normal[2] > 0.5)
+                .count()
+        };
+        assert_eq!(top(&whole), 6 * top(&triangles));
     }
 
-    // 法線を計算してTriangleに変換
-    facets.into_iter().map(Facet::to_triangle).collect()
+    /// Volume enclosed by `triangles`, positive if they face outward.
+    fn volume(triangles: &[Triangle]) -> f32 {
+        triangles
+            .iter()
+            .map(|triangle| {
+                let [a, b, c] = triangle.vertices.map(|v| Vec3::new(v[0], v[1], v[2]));
+                a.dot(b.cross(c)) / 6.0
+            })
+            .sum()
+    }
+
+    #[test]
+    fn hollow_shell_is_watertight_and_lighter() {
+        let cells = hexagon(15, 5);
+        let solid = cells_to_triangles(&cells, Lattice::Hex, 1.0, 10.0, Relief::default(), &|_| {});
+        let hollow = cells_to_hollow_triangles(
+            &cells,
+            Lattice::Hex,
+            1.0,
+            10.0,
+            Relief::default(),
+            2.0,
+            &|_| {},
+        );
+        assert_eq!(count_open_edges(&hollow), 0);
+        // 2歩内側の半径3の六角形が空洞になる
+        assert!(hollow.len() > solid.len());
+        let (solid, hollow) = (volume(&solid), volume(&hollow));
+        assert!(0.0 < hollow && hollow < solid * 0.8, "{hollow} {solid}");
+    }
+
+    #[test]
+    fn thin_crystal_stays_solid_when_hollowed() {
+        let cells = hexagon(9, 1);
+        let solid = cells_to_triangles(&cells, Lattice::Hex, 1.0, 1.0, Relief::default(), &|_| {});
+        let hollow = cells_to_hollow_triangles(
+            &cells,
+            Lattice::Hex,
+            1.0,
+            1.0,
+            Relief::default(),
+            1.0,
+            &|_| {},
+        );
+        // 1歩削ると中心しか残らず、三角形にならない
+        assert_eq!(hollow.len(), solid.len());
+    }
+
+    #[test]
+    fn outline_ribbons_are_closed() {
+        let cells = hexagon(9, 2);
+        let triangles = cells_to_outline_triangles(
+            &cells,
+            Lattice::Hex,
+            XY_SCALE,
+            Z_SCALE,
+            Relief::default(),
+            0.5,
+            &|_| {},
+        );
+        // 外周の12辺がそれぞれ12枚の三角形の箱になる
+        assert_eq!(triangles.len(), 12 * 12);
+        for ribbon in triangles.chunks(12) {
+            assert_eq!(count_open_edges(ribbon), 0);
+        }
+    }
+
+    #[test]
+    fn binary_stl_has_a_record_per_triangle() {
+        let cells = hexagon(9, 2);
+        let triangles = cells_to_triangles(
+            &cells,
+            Lattice::Hex,
+            XY_SCALE,
+            Z_SCALE,
+            Relief::default(),
+            &|_| {},
+        );
+        let bytes = stl_bytes(&cells, StlOptions::default(), &|_| {}).unwrap();
+        // 80バイトのヘッダと三角形の数の後に、三角形ごとに50バイト
+        assert_eq!(bytes.len(), 84 + 50 * triangles.len());
+    }
+
+    #[test]
+    fn pinched_crystal_is_watertight() {
+        // 2つの三角形が(3, 3)の1点だけで接している
+        let mut cells = Array2::zeros((7, 7));
+        for (i, j) in [(3, 3), (4, 3), (3, 4), (2, 3), (3, 2)] {
+            cells[[i, j]] = 1.0;
+        }
+        let triangles = cells_to_triangles(
+            &cells,
+            Lattice::Hex,
+            XY_SCALE,
+            Z_SCALE,
+            Relief::default(),
+            &|_| {},
+        );
+        assert_eq!(triangles.len(), 2 * 2 + 6 * 2);
+        assert_eq!(count_open_edges(&triangles), 0);
+    }
 }
@@ -0,0 +1,76 @@
+use std::{
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use ndarray::Array2;
+
+use crate::{contour::extract_contours, gravner_griffeath::Lattice};
+
+/// Blank space around the outline, in cell spacings.
+const MARGIN: f32 = 2.0;
+
+/// Writes the outline of the crystal on `lattice` as SVG paths.
+///
+/// With `fill`, the outline is filled (holes are left open by the even-odd rule);
+/// otherwise only the stroke is drawn, which suits laser cutters.
+pub fn write_to_svg(
+    cells: &Array2<f32>,
+    lattice: Lattice,
+    path: &Path,
+    fill: bool,
+) -> std::io::Result<()> {
+    let contours = extract_contours(&cells.mapv(|c| c > 0.0));
+    // 格子座標から平面座標へ。SVGはy軸が下向きなので反転する
+    let polygons: Vec<Vec<(f32, f32)>> = contours
+        .iter()
+        .map(|contour| {
+            contour
+                .iter()
+                .map(|&(i, j)| {
+                    let p = lattice.position(i as f32, j as f32);
+                    (p.x, -p.y)
+                })
+                .collect()
+        })
+        .collect();
+    let (min_x, min_y, max_x, max_y) = polygons.iter().flatten().fold(
+        (f32::MAX, f32::MAX, f32::MIN, f32::MIN),
+        |(min_x, min_y, max_x, max_y), &(x, y)| {
+            (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y))
+        },
+    );
+    let (min_x, min_y, max_x, max_y) = if polygons.is_empty() {
+        (0.0, 0.0, 0.0, 0.0)
+    } else {
+        (min_x, min_y, max_x, max_y)
+    };
+    let width = max_x - min_x + MARGIN * 2.0;
+    let height = max_y - min_y + MARGIN * 2.0;
+
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    writeln!(
+        writer,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="{} {} {width} {height}">"#,
+        min_x - MARGIN,
+        min_y - MARGIN,
+    )?;
+    let style = if fill {
+        r#"fill="black" fill-rule="evenodd" stroke="none""#
+    } else {
+        r#"fill="none" stroke="black" stroke-width="0.1""#
+    };
+    write!(writer, r#"<path {style} d=""#)?;
+    for polygon in &polygons {
+        for (k, (x, y)) in polygon.iter().enumerate() {
+            write!(writer, "{}{x} {y} ", if k == 0 { "M" } else { "L" })?;
+        }
+        write!(writer, "Z ")?;
+    }
+    writeln!(writer, r#""/>"#)?;
+    writeln!(writer, "</svg>")?;
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,178 @@
+//! Grids of Gravner-Griffeath parameter values run one after another in headless mode.
+
+use std::str::FromStr;
+
+use crate::gravner_griffeath::Parameter;
+
+/// Evenly spaced values of one parameter, written `name=start:end:count`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SweepAxis {
+    pub parameter: Parameter,
+    pub start: f32,
+    pub end: f32,
+    pub count: usize,
+}
+
+impl SweepAxis {
+    /// `count` values from `start` to `end`, both included; only `start` if `count` is 1.
+    pub fn values(&self) -> Vec<f32> {
+        if self.count == 1 {
+            return vec![self.start];
+        }
+        (0..self.count)
+            .map(|k| self.start + (self.end - self.start) * k as f32 / (self.count - 1) as f32)
+            .collect()
+    }
+}
+
+impl FromStr for SweepAxis {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, range) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected name=start:end:count, got {s}"))?;
+        let parameter = Parameter::ALL
+            .into_iter()
+            .find(|parameter| parameter.name() == name)
+            .ok_or_else(|| format!("unknown parameter {name}"))?;
+        let [start, end, count] = range
+            .split(':')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| format!("expected start:end:count, got {range}"))?;
+        let value = |text: &str| -> Result<f32, String> {
+            let value: f32 = text.parse().map_err(|e| format!("{text}: {e}"))?;
+            // NaN もここで弾く
+            if (0.0..=parameter.max()).contains(&value) && value.is_finite() {
+                Ok(value)
+            } else {
+                Err(format!("{name} = {value} is out of range"))
+            }
+        };
+        let count: usize = count.parse().map_err(|e| format!("{count}: {e}"))?;
+        if count == 0 {
+            return Err("the count must be at least 1".to_string());
+        }
+        Ok(Self {
+            parameter,
+            start: value(start)?,
+            end: value(end)?,
+            count,
+        })
+    }
+}
+
+/// Every combination of the values of `axes`, the last axis changing fastest.
+pub fn combinations(axes: &[SweepAxis]) -> Vec<Vec<(Parameter, f32)>> {
+    axes.iter().fold(vec![Vec::new()], |combinations, axis| {
+        combinations
+            .into_iter()
+            .flat_map(|combination| {
+                axis.values().into_iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.push((axis.parameter, value));
+                    combination
+                })
+            })
+            .collect()
+    })
+}
+
+/// Name of the files of the `index`th of `count` runs without the extension, such as
+/// `snowflake-sweep-05-beta1.4-rho0.5`.
+///
+/// The number keeps the names apart even when the values round to the same text.
+pub fn file_stem(index: usize, count: usize, combination: &[(Parameter, f32)]) -> String {
+    let width = count.to_string().len();
+    let mut stem = format!("snowflake-sweep-{:0width$}", index + 1);
+    for (parameter, value) in combination {
+        stem += &format!("-{}{}", parameter.name(), short_number(*value));
+    }
+    stem
+}
+
+/// Values of one run for its label on the contact sheet, such as `β=1.4 ρ=0.5`.
+pub fn label(combination: &[(Parameter, f32)]) -> String {
+    combination
+        .iter()
+        .map(|(parameter, value)| format!("{}={}", parameter.symbol(), short_number(*value)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `value` with at most four significant digits and no trailing zeros, hiding the rounding of
+/// the steps.
+pub fn short_number(value: f32) -> String {
+    // 小数の桁数で丸めると γ のような小さな値の刻みが消えるので、有効数字で丸める
+    let decimals = if value == 0.0 {
+        0
+    } else {
+        (3 - value.abs().log10().floor() as i32).max(0) as usize
+    };
+    let text = format!("{value:.decimals$}");
+    if text.contains('.') {
+        text.trim_end_matches('0').trim_end_matches('.').to_string()
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn axes_are_parsed_and_checked() {
+        let axis: SweepAxis = "beta=1.2:2.0:5".parse().unwrap();
+        assert_eq!(axis.parameter, Parameter::Beta);
+        let values: Vec<String> = axis.values().into_iter().map(short_number).collect();
+        assert_eq!(values, ["1.2", "1.4", "1.6", "1.8", "2"]);
+        assert!("kappa=0:2:3".parse::<SweepAxis>().is_err());
+        assert!("beta=1:2".parse::<SweepAxis>().is_err());
+        assert!("beta=1:2:0".parse::<SweepAxis>().is_err());
+        assert!("lambda=1:2:3".parse::<SweepAxis>().is_err());
+    }
+
+    #[test]
+    fn combinations_cover_the_grid() {
+        let axes = [
+            "beta=1.2:2.0:5".parse().unwrap(),
+            "rho=0.4:0.6:3".parse().unwrap(),
+        ];
+        let combinations = combinations(&axes);
+        assert_eq!(combinations.len(), 15);
+        // 後の軸が先に変わる
+        assert_eq!(
+            file_stem(0, 15, &combinations[0]),
+            "snowflake-sweep-01-beta1.2-rho0.4"
+        );
+        assert_eq!(
+            file_stem(1, 15, &combinations[1]),
+            "snowflake-sweep-02-beta1.2-rho0.5"
+        );
+        assert_eq!(
+            file_stem(14, 15, &combinations[14]),
+            "snowflake-sweep-15-beta2-rho0.6"
+        );
+        assert_eq!(label(&combinations[1]), "β=1.2 ρ=0.5");
+    }
+
+    #[test]
+    fn small_steps_get_distinct_names() {
+        let axis: SweepAxis = "gamma=0.00001:0.0001:10".parse().unwrap();
+        let combinations = combinations(&[axis]);
+        let labels: std::collections::HashSet<String> =
+            combinations.iter().map(|c| label(c)).collect();
+        assert_eq!(labels.len(), 10);
+        assert_eq!(label(&combinations[0]), "γ=0.00001");
+        assert_eq!(label(&combinations[9]), "γ=0.0001");
+        // 丸めて同じ値になっても、番号でファイル名は重ならない
+        let stems: std::collections::HashSet<String> = [0.10001, 0.10002]
+            .iter()
+            .enumerate()
+            .map(|(k, &value)| file_stem(k, 2, &[(Parameter::Gamma, value)]))
+            .collect();
+        assert_eq!(stems.len(), 2);
+    }
+}
@@ -0,0 +1,184 @@
+//! Analysis of how six-fold symmetric the crystal is.
+
+use std::time::Duration;
+
+use bevy::{prelude::*, utils::Instant};
+use bevy_egui::{egui, EguiContexts};
+use ndarray::Array2;
+
+use crate::{
+    gravner_griffeath::{rotational_orbit, Lattice},
+    Field,
+};
+
+/// Angular sectors the crystal mass is binned into, 5° each.
+const SECTORS: usize = 72;
+/// The analysis is recomputed at most this often while the window is open.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Frozen mass around the center by angle and the symmetry error, recomputed periodically.
+#[derive(Resource, Default)]
+pub struct SymmetryAnalysis {
+    /// mass of each sector, counterclockwise from the one centered on the +i axis
+    mass: Vec<f32>,
+    /// see [`symmetry_error`]
+    error: f32,
+    computed_at: Option<(u64, Instant)>,
+}
+
+impl SymmetryAnalysis {
+    /// Recomputes the analysis if the field has advanced and the last one is old enough.
+    fn update(&mut self, field: &Field) {
+        if self
+            .computed_at
+            .is_some_and(|(_, at)| at.elapsed() < UPDATE_INTERVAL)
+        {
+            return;
+        }
+        let field = field.0.read();
+        if self.computed_at.is_some_and(|(step, _)| step == field.step) {
+            return;
+        }
+        self.mass = angular_mass(&field.cells, SECTORS);
+        self.error = symmetry_error(&field.cells);
+        self.computed_at = Some((field.step, Instant::now()));
+    }
+}
+
+/// Sum of the frozen mass in each of `sectors` equal angular sectors around `(n / 2, n / 2)`.
+///
+/// The `k`-th sector is centered on the angle `k / sectors` of a full turn. The angle is measured on the hexagonal lattice as shown on screen, counterclockwise from the
+/// +i axis, so a symmetric crystal has six equal peaks. Frozen cells are those with a positive
+/// mass in `cells`; the center cell has no angle and is left out.
+pub fn angular_mass(cells: &Array2<f32>, sectors: usize) -> Vec<f32> {
+    let center = (cells.shape()[0] / 2) as f32;
+    let mut mass = vec![0.0; sectors];
+    for ((i, j), &c) in cells.indexed_iter() {
+        if c <= 0.0 {
+            continue;
+        }
+        let p = Lattice::Hex.position(i as f32 - center, j as f32 - center);
+        if p == Vec2::ZERO {
+            continue;
+        }
+        let angle = p.y.atan2(p.x).rem_euclid(std::f32::consts::TAU);
+        // 格子の軸が扇形の境目に来ないよう、半分ずらして軸を中央にする
+        let sector = (angle / std::f32::consts::TAU * sectors as f32 + 0.5) as usize;
+        mass[sector % sectors] += c;
+    }
+    mass
+}
+
+/// How far the frozen mass is from six-fold symmetry, from 0 (symmetric) to 1.
+///
+/// The squared differences between the mass of each cell and of its five rotations by multiples
+/// of 60° are summed and divided by their largest possible value, ten times the sum of the
+/// squared masses. The rotations map the lattice onto itself exactly in axial coordinates, so no
+/// interpolation is needed; cells whose rotations leave the grid are left out.
+pub fn symmetry_error(cells: &Array2<f32>) -> f32 {
+    let n = cells.shape()[0];
+    let (mut difference, mut norm) = (0.0f64, 0.0f64);
+    for ((i, j), &c) in cells.indexed_iter() {
+        let Some(orbit) = rotational_orbit(i, j, n) else {
+            continue;
+        };
+        // 回転先だけが凍っている場合も数えるよう、凍っていないセルも回る
+        let c = c as f64;
+        norm += c * c;
+        for &(ri, rj) in &orbit[1..] {
+            let rotated = cells[[ri, rj]] as f64;
+            difference += (c - rotated) * (c - rotated);
+        }
+    }
+    if norm > 0.0 {
+        (difference / (10.0 * norm)) as f32
+    } else {
+        0.0
+    }
+}
+
+pub fn configure_ui(
+    mut contexts: EguiContexts,
+    field: Res<Field>,
+    mut analysis: ResMut<SymmetryAnalysis>,
+) {
+    egui::Window::new("Symmetry")
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            // 開いている間だけ集計する
+            analysis.update(&field);
+            let sector = std::f64::consts::TAU / analysis.mass.len().max(1) as f64;
+            let max = analysis.mass.iter().copied().fold(0.0, f32::max);
+            // 各扇形の中心の角度に、最大値を1とした質量を半径として置く
+            let mut points: Vec<[f64; 2]> = analysis
+                .mass
+                .iter()
+                .enumerate()
+                .map(|(k, &m)| {
+                    let angle = k as f64 * sector;
+                    let radius = if max > 0.0 { (m / max) as f64 } else { 0.0 };
+                    [radius * angle.cos(), radius * angle.sin()]
+                })
+                .collect();
+            points.extend(points.first().copied());
+            let guides = (0..6).map(|k| {
+                let angle = k as f64 * std::f64::consts::FRAC_PI_3;
+                egui_plot::Line::new(vec![[0.0, 0.0], [angle.cos(), angle.sin()]])
+                    .color(egui::Color32::DARK_GRAY)
+            });
+            egui_plot::Plot::new("angular mass")
+                .width(240.0)
+                .height(240.0)
+                .data_aspect(1.0)
+                .show_axes(false)
+                .allow_drag(false)
+                .allow_zoom(false)
+                .allow_scroll(false)
+                .include_x(-1.0)
+                .include_x(1.0)
+                .include_y(-1.0)
+                .include_y(1.0)
+                .show(ui, |plot_ui| {
+                    for guide in guides {
+                        plot_ui.line(guide);
+                    }
+                    plot_ui.line(egui_plot::Line::new(points).name("mass"));
+                });
+            ui.label("Frozen mass by angle, relative to the largest sector")
+                .on_hover_text("A six-fold symmetric crystal has six equal peaks along the guides");
+            ui.label(format!("Symmetry error: {:.4}", analysis.error)).on_hover_text(
+                "Squared difference from the crystal rotated by 60° steps; 0 is perfectly symmetric",
+            );
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symmetry_error_is_zero_only_for_symmetric_crystals() {
+        let mut cells = Array2::zeros((9, 9));
+        cells[[4, 4]] = 1.0;
+        for (di, dj) in crate::gravner_griffeath::DIRECTIONS {
+            cells[[(4 + di) as usize, (4 + dj) as usize]] = 0.5;
+        }
+        assert_eq!(symmetry_error(&cells), 0.0);
+        // 1方向だけに伸びた腕は、どの回転とも重ならない
+        cells[[6, 4]] = 0.5;
+        let error = symmetry_error(&cells);
+        assert!(error > 0.0 && error <= 1.0, "{error}");
+    }
+
+    #[test]
+    fn angular_mass_bins_by_direction_on_screen() {
+        let mut cells = Array2::zeros((5, 5));
+        // 中心は角度を持たないので数えない
+        cells[[2, 2]] = 1.0;
+        // +i 方向は0°、+j 方向は画面上で60°
+        cells[[3, 2]] = 2.0;
+        cells[[2, 3]] = 3.0;
+        let mass = angular_mass(&cells, 6);
+        assert_eq!(mass, vec![2.0, 3.0, 0.0, 0.0, 0.0, 0.0]);
+    }
+}
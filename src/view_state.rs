@@ -0,0 +1,134 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::PathBuf,
+};
+
+use bevy::{prelude::*, window::PrimaryWindow};
+
+/// File next to the executable that keeps the view between launches.
+#[cfg(not(target_arch = "wasm32"))]
+const VIEW_STATE_FILE: &str = "snowflake-view.json";
+
+/// Window size and 2D camera of the last session, restored on the next launch.
+#[derive(Resource, Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ViewState {
+    /// logical size of the primary window, if it has been shown
+    pub window_size: Option<[f32; 2]>,
+    /// scale of the 2D camera's projection
+    pub zoom: f32,
+    /// translation of the 2D camera
+    pub pan: [f32; 2],
+}
+
+impl Default for ViewState {
+    fn default() -> Self {
+        Self {
+            window_size: None,
+            zoom: 1.0,
+            pan: [0.0, 0.0],
+        }
+    }
+}
+
+impl ViewState {
+    /// Reads the saved view, falling back to the defaults if it is missing or broken.
+    pub fn load() -> Self {
+        match read_view_state() {
+            Ok(state) => state,
+            Err(e) if is_unavailable(&e) => Self::default(),
+            Err(e) => {
+                tracing::warn!("Ignoring the saved view: {e}");
+                Self::default()
+            }
+        }
+    }
+
+    fn save(&self) -> std::io::Result<PathBuf> {
+        let path = view_state_path()?;
+        let mut writer = BufWriter::new(File::create(&path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+        Ok(path)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn view_state_path() -> std::io::Result<PathBuf> {
+    Ok(std::env::current_exe()?.with_file_name(VIEW_STATE_FILE))
+}
+
+// ブラウザではファイルに保存できないので、常に既定値を使う
+#[cfg(target_arch = "wasm32")]
+fn view_state_path() -> std::io::Result<PathBuf> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Whether there is simply no saved view, as opposed to a broken one.
+fn is_unavailable(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::NotFound | std::io::ErrorKind::Unsupported
+    )
+}
+
+fn read_view_state() -> std::io::Result<ViewState> {
+    let reader = BufReader::new(File::open(view_state_path()?)?);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// Applies the saved window size to the primary window.
+pub fn restore_window(
+    view_state: Res<ViewState>,
+    mut window_query: Query<&mut Window, With<PrimaryWindow>>,
+) {
+    if let (Some([width, height]), Ok(mut window)) =
+        (view_state.window_size, window_query.get_single_mut())
+    {
+        window.resolution.set(width, height);
+    }
+}
+
+/// Keeps the window size up to date, since the window is gone by the time the app exits.
+pub fn track_window(
+    mut view_state: ResMut<ViewState>,
+    window_query: Query<&Window, With<PrimaryWindow>>,
+) {
+    let Ok(window) = window_query.get_single() else {
+        return;
+    };
+    let size = Some([window.width(), window.height()]);
+    if view_state.window_size != size {
+        view_state.window_size = size;
+    }
+}
+
+/// Writes the view when the app exits.
+pub fn save_on_exit(mut exit_events: EventReader<AppExit>, view_state: Res<ViewState>) {
+    if exit_events.read().next().is_none() {
+        return;
+    }
+    match view_state.save() {
+        Ok(path) => tracing::info!("Saved view: {}", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {}
+        Err(e) => tracing::error!("Failed to save view: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_fields_use_defaults() {
+        let state: ViewState = serde_json::from_str(r#"{"zoom": 0.5}"#).unwrap();
+        assert_eq!(
+            state,
+            ViewState {
+                zoom: 0.5,
+                ..ViewState::default()
+            }
+        );
+    }
+}
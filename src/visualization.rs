@@ -1,14 +1,74 @@
-use bevy::{prelude::*, sprite::Mesh2dHandle};
+use bevy::{
+    asset::load_internal_asset,
+    ecs::system::SystemParam,
+    input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel},
+    prelude::*,
+    render::{
+        mesh::{Indices, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+        render_resource::{AsBindGroup, Extent3d, ShaderRef, TextureDimension, TextureFormat},
+    },
+    sprite::{Material2d, Material2dPlugin, MaterialMesh2dBundle, Mesh2dHandle},
+    window::PrimaryWindow,
+};
+use bevy_egui::{egui, EguiContexts};
+use ndarray::Array2;
+use parking_lot::RwLockWriteGuard;
 
-use crate::Field;
+use crate::{
+    compare::Comparison,
+    gravner_griffeath::{FieldLayer, Lattice},
+    stl::HeightMapping,
+    view_state::ViewState,
+    Brush, ControlEvent, ExportSettings, Field, Mirror, Model, PaintKind,
+};
 
 pub struct VisualizationPlugin;
 
 impl Plugin for VisualizationPlugin {
     fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            HEX_GRID_SHADER_HANDLE,
+            "hex_grid.wgsl",
+            Shader::from_wgsl
+        );
+        app.add_plugins(Material2dPlugin::<HexGridMaterial>::default());
         app.init_resource::<Coordinates>();
+        app.init_resource::<ColorMap>();
+        app.init_resource::<ViewMode>();
+        app.init_resource::<FrontOverlay>();
+        app.init_resource::<CellBorders>();
+        app.init_resource::<AxesOverlay>();
+        app.init_resource::<PaintTool>();
+        app.init_resource::<Ruler>();
+        app.add_event::<FitToCrystal>();
         app.add_systems(Startup, setup);
-        app.add_systems(Update, update_visualization);
+        app.add_systems(
+            Update,
+            (
+                switch_view,
+                resize_grid,
+                update_borders,
+                update_visualization.run_if(resource_equals(ViewMode::Flat)),
+                update_surface.run_if(resource_equals(ViewMode::Surface)),
+            )
+                .chain(),
+        );
+        app.add_systems(
+            Update,
+            (
+                zoom_and_pan,
+                fit_to_crystal,
+                track_camera,
+                paint_cells,
+                measure_distance,
+                draw_growth_axes,
+                draw_ruler,
+            )
+                .chain()
+                .run_if(resource_equals(ViewMode::Flat)),
+        );
     }
 }
 
@@ -23,88 +83,1279 @@ impl Default for Coordinates {
     }
 }
 
+/// Bounds of the 2D camera's projection scale; smaller is closer.
+const MIN_ZOOM: f32 = 0.05;
+const MAX_ZOOM: f32 = 5.0;
+
+/// How a cell is drawn into the texture of the [`CellGrid`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CellColor {
+    Hidden,
+    /// entry of the [`ColorMap`]
+    Palette(u8),
+    /// growth front of the [`FrontOverlay`]
+    Front,
+    /// entry of the diverging palette of a [`Comparison`], blue for -1 to red for 1
+    Difference(u8),
+}
+
+impl CellColor {
+    /// Texel of the color, with `palette` holding the 256 entries of the [`ColorMap`].
+    fn rgba(self, palette: &[[u8; 4]]) -> [u8; 4] {
+        match self {
+            CellColor::Hidden => [0; 4],
+            CellColor::Palette(k) => palette[k as usize],
+            CellColor::Front => FRONT_COLOR.to_srgba().to_u8_array(),
+            CellColor::Difference(k) => diverging_color(k).to_srgba().to_u8_array(),
+        }
+    }
+}
+
+/// Palette that the cell values are looked up from.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorMap {
+    /// white, fading out with the mass
+    #[default]
+    Grayscale,
+    Viridis,
+    Ice,
+    Heat,
+}
+
+impl ColorMap {
+    pub const ALL: [ColorMap; 4] = [
+        ColorMap::Grayscale,
+        ColorMap::Viridis,
+        ColorMap::Ice,
+        ColorMap::Heat,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ColorMap::Grayscale => "Grayscale",
+            ColorMap::Viridis => "Viridis",
+            ColorMap::Ice => "Ice",
+            ColorMap::Heat => "Heat",
+        }
+    }
+
+    /// Color of the `index`-th of the 256 palette entries.
+    ///
+    /// The cells use the entries in reverse, so the largest mass gets entry 1.
+    fn color(&self, index: u8) -> Color {
+        let t = index as f32 / 255.0;
+        let stops: &[[f32; 3]] = match self {
+            // 従来どおり白の透明度だけを変える
+            ColorMap::Grayscale => return Color::WHITE.with_alpha(t),
+            ColorMap::Viridis => &[
+                [0.267, 0.005, 0.329],
+                [0.230, 0.322, 0.546],
+                [0.128, 0.567, 0.551],
+                [0.369, 0.789, 0.383],
+                [0.993, 0.906, 0.144],
+            ],
+            ColorMap::Ice => &[
+                [0.02, 0.05, 0.25],
+                [0.10, 0.35, 0.70],
+                [0.50, 0.80, 0.95],
+                [1.0, 1.0, 1.0],
+            ],
+            ColorMap::Heat => &[
+                [0.0, 0.0, 0.0],
+                [0.8, 0.0, 0.0],
+                [1.0, 0.8, 0.0],
+                [1.0, 1.0, 1.0],
+            ],
+        };
+        // 質量の大きいセルほど番号が小さいので、逆順にたどる
+        let x = (1.0 - t) * (stops.len() - 1) as f32;
+        let k = (x as usize).min(stops.len() - 2);
+        let f = x - k as f32;
+        let [r, g, b] = [0, 1, 2].map(|c| stops[k][c] + (stops[k + 1][c] - stops[k][c]) * f);
+        Color::srgb(r, g, b)
+    }
+}
+
+/// How the crystal is shown on screen.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ViewMode {
+    /// hexagon grid seen from above
+    #[default]
+    Flat,
+    /// relief of the crystal mass, as in the exported STL
+    Surface,
+}
+
+impl ViewMode {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ViewMode::Flat => "2D",
+            ViewMode::Surface => "3D",
+        }
+    }
+}
+
+/// Highlights the growth front, the cells about to freeze, in the 2D view.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrontOverlay {
+    pub is_enabled: bool,
+}
+
+/// Color of the growth front cells, apart from those of all the palettes.
+const FRONT_COLOR: Color = Color::srgb(1.0, 0.2, 0.8);
+
+/// Outlines the cells in the 2D view, so that single frozen cells stand out at the tips.
+///
+/// Faint cells are also drawn more opaque while it is enabled.
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct CellBorders {
+    pub is_enabled: bool,
+    /// width of the lines in cell spacings
+    pub width: f32,
+}
+
+impl Default for CellBorders {
+    fn default() -> Self {
+        Self {
+            is_enabled: false,
+            width: 0.1,
+        }
+    }
+}
+
+/// Draws guide lines from the center of the grid along the lattice directions in the 2D view,
+/// to see how the arms line up with the lattice.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AxesOverlay {
+    pub is_enabled: bool,
+}
+
+/// Color of the lines of the [`AxesOverlay`].
+const AXES_COLOR: Color = Color::srgba(0.3, 0.8, 1.0, 0.6);
+
+/// Measures the distance between two cells clicked in the 2D view, in cell spacings and in
+/// the units of the exported mesh; the left button sets its ends instead of painting.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Ruler {
+    pub is_enabled: bool,
+    /// centers of the clicked cells in cell spacings, as in [`cell_position`]
+    pub start: Option<Vec2>,
+    pub end: Option<Vec2>,
+}
+
+impl Ruler {
+    /// Distance between the ends in cell spacings, once both are set.
+    pub fn distance(&self) -> Option<f32> {
+        Some(self.start?.distance(self.end?))
+    }
+}
+
+/// Color of the line and the ends of the [`Ruler`].
+const RULER_COLOR: Color = Color::srgb(1.0, 0.85, 0.2);
+
+/// Opacity of the faintest cells while the [`CellBorders`] are shown.
+const MIN_ALPHA_WITH_BORDERS: f32 = 0.3;
+
+/// Edits the cells under the cursor while the left button is held on the 2D view.
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct PaintTool {
+    /// `None` leaves the left button alone
+    pub kind: Option<PaintKind>,
+    /// vapor added to each painted cell
+    pub vapor: f32,
+    /// lattice distance from the cursor within which the cells are painted
+    pub radius: usize,
+    pub mirror: Mirror,
+}
+
+impl Default for PaintTool {
+    fn default() -> Self {
+        Self {
+            kind: None,
+            vapor: 0.5,
+            radius: 0,
+            mirror: Mirror::Off,
+        }
+    }
+}
+
+/// Request to center the 2D view on the crystal and zoom so that it fills the window.
+#[derive(Event, Default)]
+pub struct FitToCrystal;
+
+/// Key that sends [`FitToCrystal`].
+const FIT_KEY: KeyCode = KeyCode::KeyF;
+
+/// Settings of how the crystal is shown, changed from the control window.
+#[derive(SystemParam)]
+pub struct ViewSettings<'w> {
+    pub color_map: ResMut<'w, ColorMap>,
+    pub view_mode: ResMut<'w, ViewMode>,
+    pub front_overlay: ResMut<'w, FrontOverlay>,
+    pub cell_borders: ResMut<'w, CellBorders>,
+    pub axes_overlay: ResMut<'w, AxesOverlay>,
+    pub paint_tool: ResMut<'w, PaintTool>,
+    pub ruler: ResMut<'w, Ruler>,
+    pub fit: EventWriter<'w, FitToCrystal>,
+}
+
+#[derive(Component)]
+struct FlatCamera;
+
+#[derive(Component)]
+struct SurfaceCamera;
+
+/// Mesh of the crystal surface, rebuilt from the field while in [`ViewMode::Surface`].
+#[derive(Component)]
+struct Surface;
+
+/// Handle of `hex_grid.wgsl`, loaded by the [`VisualizationPlugin`].
+const HEX_GRID_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(0x5a3e_1f2c_9b47_4d8e_a6c1_7e20_3b9f_d415);
+
+/// Draws the whole grid on one quad, finding the hexagon, or the square on the square
+/// lattices, under each pixel in the shader and its color in a texture of one texel per cell.
+#[derive(Asset, TypePath, AsBindGroup, Clone)]
+struct HexGridMaterial {
+    #[uniform(0)]
+    grid: HexGridUniform,
+    #[texture(1)]
+    cells: Handle<Image>,
+}
+
+use hex_grid_uniform::HexGridUniform;
+
+// ShaderType の derive が生成する検査関数が使われないと警告されるので、モジュールごと抑える
+mod hex_grid_uniform {
+    #![allow(dead_code)]
+
+    use bevy::render::render_resource::ShaderType;
+
+    use super::{CellBorders, Lattice};
+
+    #[derive(ShaderType, Clone, Copy, Debug)]
+    pub(super) struct HexGridUniform {
+        n: u32,
+        /// spacing of the cells
+        scale: f32,
+        /// width of the cell borders in cell spacings, 0 for none
+        border: f32,
+        /// 0 for the hexagonal lattice, otherwise square cells; keeps the uniform 16 bytes for
+        /// WebGL2
+        lattice: u32,
+    }
+
+    impl HexGridUniform {
+        pub(super) fn new(n: usize, scale: f32, borders: CellBorders, lattice: Lattice) -> Self {
+            Self {
+                n: n as u32,
+                scale,
+                border: if borders.is_enabled {
+                    borders.width
+                } else {
+                    0.0
+                },
+                lattice: lattice as u32,
+            }
+        }
+    }
+}
+
+impl Material2d for HexGridMaterial {
+    fn fragment_shader() -> ShaderRef {
+        HEX_GRID_SHADER_HANDLE.into()
+    }
+}
+
+/// The quad that the [`HexGridMaterial`] is drawn on.
 #[derive(Component)]
-struct Cell(usize, usize, u8);
+struct GridQuad;
 
+/// Texture of the cell colors and the grid size and lattice it was made for.
 #[derive(Resource)]
-struct MaterialHandles {
-    handles: Vec<Handle<ColorMaterial>>,
+struct CellGrid {
+    n: usize,
+    lattice: Lattice,
+    image: Handle<Image>,
+    material: Handle<HexGridMaterial>,
+}
+
+/// Rectangle of cells on the grid, both corners included.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CellRegion {
+    pub min: [usize; 2],
+    pub max: [usize; 2],
 }
 
+impl CellRegion {
+    /// Smallest region holding the cells that differ between `old` and `new`, or the whole of
+    /// `new` if the grid size has changed.
+    pub fn of_changes(old: &Array2<f32>, new: &Array2<f32>) -> Option<Self> {
+        let (rows, columns) = new.dim();
+        if old.dim() != new.dim() {
+            return (rows > 0 && columns > 0).then_some(Self {
+                min: [0, 0],
+                max: [rows - 1, columns - 1],
+            });
+        }
+        let mut region: Option<Self> = None;
+        for (((i, j), &a), &b) in old.indexed_iter().zip(new.iter()) {
+            // NaNどうしも同じとみなす
+            if a.to_bits() == b.to_bits() {
+                continue;
+            }
+            let cell = Self {
+                min: [i, j],
+                max: [i, j],
+            };
+            region = Some(region.map_or(cell, |region| region.union(cell)));
+        }
+        region
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            min: [self.min[0].min(other.min[0]), self.min[1].min(other.min[1])],
+            max: [self.max[0].max(other.max[0]), self.max[1].max(other.max[1])],
+        }
+    }
+
+    /// Grows the region by `margin` cells on every side, within a grid of size `dim`.
+    fn expand(self, margin: usize, (rows, columns): (usize, usize)) -> Self {
+        Self {
+            min: self.min.map(|k| k.saturating_sub(margin)),
+            max: [
+                (self.max[0] + margin).min(rows - 1),
+                (self.max[1] + margin).min(columns - 1),
+            ],
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut standard_materials: ResMut<Assets<StandardMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+    mut hex_grid_materials: ResMut<Assets<HexGridMaterial>>,
     field: Res<Field>,
     coordinates: Res<Coordinates>,
+    view_state: Res<ViewState>,
 ) {
-    let mut transform = Transform::default();
+    // 前回終了時の位置と倍率から始める
+    let [x, y] = view_state.pan;
+    let mut transform = Transform::from_xyz(x, y, 0.0);
     transform.rotate_z(30f32.to_radians());
-    commands.spawn(Camera2dBundle {
+    let mut camera = Camera2dBundle {
         transform,
         ..default()
+    };
+    camera.projection.scale = view_state.zoom.clamp(MIN_ZOOM, MAX_ZOOM);
+    commands.spawn((FlatCamera, camera));
+    let (n, lattice) = {
+        let field = field.0.read();
+        (field.cells.shape()[0], field.lattice)
+    };
+
+    // 3D表示。切り替えるまではカメラを止めておく
+    let distance = n as f32 * coordinates.scale;
+    commands.spawn((
+        SurfaceCamera,
+        Camera3dBundle {
+            camera: Camera {
+                is_active: false,
+                ..default()
+            },
+            transform: Transform::from_xyz(0.0, -distance * 0.6, distance * 0.6)
+                .looking_at(Vec3::ZERO, Vec3::Z),
+            ..default()
+        },
+    ));
+    commands.spawn(DirectionalLightBundle {
+        transform: Transform::from_xyz(1.0, -1.0, 2.0).looking_at(Vec3::ZERO, Vec3::Z),
+        ..default()
+    });
+    commands.spawn((
+        Surface,
+        PbrBundle {
+            mesh: meshes.add(Mesh::new(
+                PrimitiveTopology::TriangleList,
+                RenderAssetUsages::default(),
+            )),
+            material: standard_materials.add(StandardMaterial {
+                base_color: Color::srgb(0.85, 0.92, 1.0),
+                perceptual_roughness: 0.4,
+                double_sided: true,
+                cull_mode: None,
+                ..default()
+            }),
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+    ));
+    let image = images.add(cells_image(n));
+    let material = hex_grid_materials.add(HexGridMaterial {
+        grid: HexGridUniform::new(n, coordinates.scale, CellBorders::default(), lattice),
+        cells: image.clone(),
     });
-    let n = field.0.read().cells.shape()[0];
-    let hexagon =
-        Mesh2dHandle(meshes.add(RegularPolygon::new(coordinates.scale / f32::sqrt(3.0), 6)));
-    let material_handles: Vec<Handle<ColorMaterial>> = (0..256)
-        .map(|i| {
-            let alpha = i as f32 / 255.0;
-            materials.add(ColorMaterial::from(Color::WHITE.with_alpha(alpha)))
+    commands.spawn((
+        GridQuad,
+        MaterialMesh2dBundle {
+            mesh: Mesh2dHandle(meshes.add(Rectangle::new(1.0, 1.0))),
+            material: material.clone(),
+            transform: grid_transform(n, coordinates.scale, lattice),
+            ..default()
+        },
+    ));
+    commands.insert_resource(CellGrid {
+        n,
+        lattice,
+        image,
+        material,
+    });
+}
+
+/// Transparent texture of one texel per cell, with `i` along the width.
+fn cells_image(n: usize) -> Image {
+    Image::new_fill(
+        Extent3d {
+            width: n as u32,
+            height: n as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 0],
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::default(),
+    )
+}
+
+/// Scale of the unit [`GridQuad`] that covers every cell of an `n`×`n` grid.
+fn grid_transform(n: usize, scale: f32, lattice: Lattice) -> Transform {
+    let n = n as f32;
+    let size = match lattice {
+        // セルの中心は x が ±0.75n、y が ±n√3/4 の内側にあるので、六角形1つ分の余白をつけて覆う
+        Lattice::Hex => Vec2::new(1.5 * n + 2.0, n + 2.0),
+        Lattice::Square4 | Lattice::Square8 => Vec2::splat(n + 2.0),
+    };
+    Transform::from_scale((size * scale).extend(1.0))
+}
+
+/// Center of the cell `(i, j)` of an `n`×`n` grid in cell spacings, before the 30° rotation
+/// of the camera. `hex_grid.wgsl` does the inverse.
+fn cell_position(i: usize, j: usize, n: usize, lattice: Lattice) -> Vec2 {
+    let center = (n / 2) as f32;
+    match lattice {
+        Lattice::Hex => Vec2::new(
+            i as f32 + j as f32 / 2.0 - n as f32 * 0.75,
+            (j as f32 - center) * f32::sqrt(3.0) / 2.0,
+        ),
+        Lattice::Square4 | Lattice::Square8 => Vec2::new(i as f32 - center, j as f32 - center),
+    }
+}
+
+/// Lines from the center of an `n`×`n` grid to its edge along each of the directions of
+/// `lattice`, in world coordinates of the 2D view.
+fn growth_axes(n: usize, lattice: Lattice, scale: f32) -> Vec<(Vec2, Vec2)> {
+    let c = n / 2;
+    let center = cell_position(c, c, n, lattice);
+    lattice
+        .directions()
+        .iter()
+        .map(|&(di, dj)| {
+            let step = cell_position(
+                c.saturating_add_signed(di),
+                c.saturating_add_signed(dj),
+                n,
+                lattice,
+            ) - center;
+            (center * scale, (center + step * c as f32) * scale)
         })
-        .collect();
-    commands.insert_resource(MaterialHandles {
-        handles: material_handles.clone(),
+        .collect()
+}
+
+/// Draws the [`AxesOverlay`] with gizmos, which follow the camera's rotation and zoom.
+fn draw_growth_axes(
+    axes_overlay: Res<AxesOverlay>,
+    grid: Res<CellGrid>,
+    coordinates: Res<Coordinates>,
+    mut gizmos: Gizmos,
+) {
+    if !axes_overlay.is_enabled || grid.n == 0 {
+        return;
+    }
+    for (start, end) in growth_axes(grid.n, grid.lattice, coordinates.scale) {
+        gizmos.line_2d(start, end, AXES_COLOR);
+    }
+}
+
+/// Cell of an `n`×`n` grid under the point `world` of the 2D view, the inverse of
+/// [`cell_position`] as in `hex_grid.wgsl`.
+fn cell_at(world: Vec2, n: usize, scale: f32, lattice: Lattice) -> Option<(usize, usize)> {
+    // cell_position はセル(0, 0)を原点から平行移動したもの
+    lattice.cell_at(world / scale - cell_position(0, 0, n, lattice), n)
+}
+
+/// Sends [`ControlEvent::Paint`] for each cell the cursor enters while the left button is held
+/// with a [`PaintTool`] chosen and the [`Ruler`] off.
+#[allow(clippy::too_many_arguments)]
+fn paint_cells(
+    mut contexts: EguiContexts,
+    paint_tool: Res<PaintTool>,
+    ruler: Res<Ruler>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform), With<FlatCamera>>,
+    grid: Res<CellGrid>,
+    coordinates: Res<Coordinates>,
+    field: Res<Field>,
+    mut events: EventWriter<ControlEvent>,
+    // ドラッグ中に最後に塗ったセル。同じセルを毎フレーム塗り重ねない
+    mut last: Local<Option<(usize, usize)>>,
+) {
+    let Some(kind) = paint_tool.kind.filter(|_| !ruler.is_enabled) else {
+        return;
+    };
+    if !buttons.pressed(MouseButton::Left)
+        || contexts.ctx_mut().is_pointer_over_area()
+        || field.0.read().model != Model::GravnerGriffeath
+    {
+        *last = None;
+        return;
+    }
+    let Ok((camera, global_transform)) = camera.get_single() else {
+        return;
+    };
+    let Some(cell) = window
+        .get_single()
+        .ok()
+        .and_then(Window::cursor_position)
+        .and_then(|cursor| camera.viewport_to_world_2d(global_transform, cursor))
+        .and_then(|world| cell_at(world, grid.n, coordinates.scale, grid.lattice))
+    else {
+        return;
+    };
+    if *last == Some(cell) {
+        return;
+    }
+    *last = Some(cell);
+    let (i, j) = cell;
+    events.send(ControlEvent::Paint {
+        i,
+        j,
+        brush: Brush {
+            kind,
+            radius: paint_tool.radius,
+            mirror: paint_tool.mirror,
+        },
     });
+}
 
-    for i in 0..n {
-        for j in 0..n {
-            let translation = Vec3::new(
-                i as f32 + j as f32 / 2.0 - n as f32 * 0.75,
-                (j as f32 - (n / 2) as f32) * f32::sqrt(3.0) / 2.0,
-                0.0,
-            ) * coordinates.scale;
-            commands.spawn((
-                Cell(i, j, 0),
-                ColorMesh2dBundle {
-                    visibility: Visibility::Hidden,
-                    mesh: hexagon.clone(),
-                    material: material_handles[0].clone(),
-                    transform: Transform::from_translation(translation),
-                    ..default()
-                },
-            ));
+/// Sets the ends of the [`Ruler`] at the cells clicked in the 2D view, starting over with the
+/// click after both are set.
+#[allow(clippy::too_many_arguments)]
+fn measure_distance(
+    mut contexts: EguiContexts,
+    mut ruler: ResMut<Ruler>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform), With<FlatCamera>>,
+    grid: Res<CellGrid>,
+    coordinates: Res<Coordinates>,
+    mut measured_on: Local<Option<(usize, Lattice)>>,
+) {
+    // 格子が変わると測ったセルの位置は意味を失う
+    if *measured_on != Some((grid.n, grid.lattice)) {
+        *measured_on = Some((grid.n, grid.lattice));
+        if ruler.start.is_some() {
+            ruler.start = None;
+            ruler.end = None;
         }
     }
+    if !ruler.is_enabled
+        || !buttons.just_pressed(MouseButton::Left)
+        || contexts.ctx_mut().is_pointer_over_area()
+    {
+        return;
+    }
+    let Ok((camera, global_transform)) = camera.get_single() else {
+        return;
+    };
+    let Some((i, j)) = window
+        .get_single()
+        .ok()
+        .and_then(Window::cursor_position)
+        .and_then(|cursor| camera.viewport_to_world_2d(global_transform, cursor))
+        .and_then(|world| cell_at(world, grid.n, coordinates.scale, grid.lattice))
+    else {
+        return;
+    };
+    let position = cell_position(i, j, grid.n, grid.lattice);
+    if ruler.start.is_some() && ruler.end.is_none() {
+        ruler.end = Some(position);
+    } else {
+        ruler.start = Some(position);
+        ruler.end = None;
+    }
 }
 
-fn update_visualization(
+/// Draws the [`Ruler`] with gizmos and labels its length at the middle, in cell spacings and
+/// in millimeters of the exported mesh at its xy scale.
+fn draw_ruler(
+    mut contexts: EguiContexts,
+    ruler: Res<Ruler>,
+    export_settings: Res<ExportSettings>,
+    camera: Query<(&Camera, &GlobalTransform), With<FlatCamera>>,
+    coordinates: Res<Coordinates>,
+    mut gizmos: Gizmos,
+) {
+    if !ruler.is_enabled {
+        return;
+    }
+    let scale = coordinates.scale;
+    for end in [ruler.start, ruler.end].into_iter().flatten() {
+        gizmos.circle_2d(end * scale, 0.5 * scale, RULER_COLOR);
+    }
+    let (Some(start), Some(end), Some(cells)) = (ruler.start, ruler.end, ruler.distance()) else {
+        return;
+    };
+    gizmos.line_2d(start * scale, end * scale, RULER_COLOR);
+    let Ok((camera, global_transform)) = camera.get_single() else {
+        return;
+    };
+    let Some(middle) =
+        camera.world_to_viewport(global_transform, ((start + end) / 2.0 * scale).extend(0.0))
+    else {
+        return;
+    };
+    let text = format!(
+        "{cells:.1} cells = {:.2} mm",
+        cells * export_settings.xy_scale
+    );
+    egui::Area::new(egui::Id::new("ruler_label"))
+        .fixed_pos(egui::pos2(middle.x, middle.y))
+        .interactable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label(
+                egui::RichText::new(text)
+                    .color(egui::Color32::from_rgb(255, 217, 51))
+                    .background_color(egui::Color32::from_black_alpha(160)),
+            );
+        });
+}
+
+/// Zooms the 2D view around the cursor with the mouse wheel and pans it with a middle drag.
+#[allow(clippy::too_many_arguments)]
+fn zoom_and_pan(
+    mut contexts: EguiContexts,
+    mut wheel_events: EventReader<MouseWheel>,
+    mut motion_events: EventReader<MouseMotion>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    mut camera: Query<
+        (
+            &Camera,
+            &GlobalTransform,
+            &mut Transform,
+            &mut OrthographicProjection,
+        ),
+        With<FlatCamera>,
+    >,
     field: Res<Field>,
-    mut query: Query<(&mut Cell, &mut Visibility, &mut Handle<ColorMaterial>)>,
-    material_handles: Res<MaterialHandles>,
+    coordinates: Res<Coordinates>,
 ) {
-    let new_values = {
+    // UIの上での操作はカメラに渡さない
+    if contexts.ctx_mut().is_pointer_over_area() {
+        wheel_events.clear();
+        motion_events.clear();
+        return;
+    }
+    let Ok((camera, global_transform, mut transform, mut projection)) = camera.get_single_mut()
+    else {
+        return;
+    };
+    let cursor = window.get_single().ok().and_then(Window::cursor_position);
+
+    for event in wheel_events.read() {
+        let lines = match event.unit {
+            MouseScrollUnit::Line => event.y,
+            MouseScrollUnit::Pixel => event.y / 32.0,
+        };
+        let scale = (projection.scale * 1.1f32.powf(-lines)).clamp(MIN_ZOOM, MAX_ZOOM);
+        // カーソルの下の点が動かないようにカメラをずらす
+        if let Some(anchor) =
+            cursor.and_then(|cursor| camera.viewport_to_world_2d(global_transform, cursor))
+        {
+            let anchor = anchor.extend(transform.translation.z);
+            transform.translation =
+                anchor + (transform.translation - anchor) * scale / projection.scale;
+        }
+        projection.scale = scale;
+    }
+
+    let drag: Vec2 = motion_events.read().map(|event| event.delta).sum();
+    if buttons.pressed(MouseButton::Middle) {
+        // 画面のy軸は下向き。カメラは回転しているのでその向きに合わせる
+        let delta = transform.rotation * Vec3::new(-drag.x, drag.y, 0.0) * projection.scale;
+        transform.translation += delta;
+    }
+
+    // 結晶が画面の外に出てしまわないよう、格子の範囲内に留める
+    let extent = field.0.read().cells.shape()[0] as f32 * coordinates.scale * 0.75;
+    transform.translation.x = transform.translation.x.clamp(-extent, extent);
+    transform.translation.y = transform.translation.y.clamp(-extent, extent);
+}
+
+/// Centers the 2D camera on the frozen cells and zooms to fit them, on [`FitToCrystal`] or
+/// the [`FIT_KEY`].
+fn fit_to_crystal(
+    mut contexts: EguiContexts,
+    mut events: EventReader<FitToCrystal>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut camera: Query<(&Camera, &mut Transform, &mut OrthographicProjection), With<FlatCamera>>,
+    field: Res<Field>,
+    coordinates: Res<Coordinates>,
+) {
+    let requested = events.read().count() > 0;
+    // 文字の入力中はキーを奪わない
+    let pressed = keys.just_pressed(FIT_KEY) && !contexts.ctx_mut().wants_keyboard_input();
+    if !requested && !pressed {
+        return;
+    }
+    let Ok((camera, mut transform, mut projection)) = camera.get_single_mut() else {
+        return;
+    };
+    let field = field.0.read();
+    let Some((min, max)) = crystal_bounds(
+        &field.cells,
+        field.lattice,
+        coordinates.scale,
+        transform.rotation,
+    ) else {
+        return;
+    };
+    // 画面の軸で測った範囲の中心を、回転したカメラの位置に戻す
+    let center = transform.rotation * ((min + max) / 2.0).extend(0.0);
+    transform.translation.x = center.x;
+    transform.translation.y = center.y;
+    if let Some(viewport) = camera.logical_viewport_size() {
+        // 六角形1つ分の余白を残す
+        let size = max - min + Vec2::splat(coordinates.scale * 2.0);
+        projection.scale = (size / viewport).max_element().clamp(MIN_ZOOM, MAX_ZOOM);
+    }
+}
+
+/// Bounding box of the frozen cells' centers along the axes of a camera with `rotation`.
+fn crystal_bounds(
+    cells: &Array2<f32>,
+    lattice: Lattice,
+    scale: f32,
+    rotation: Quat,
+) -> Option<(Vec2, Vec2)> {
+    let n = cells.shape()[0];
+    let inverse = rotation.inverse();
+    cells
+        .indexed_iter()
+        .filter(|(_, &c)| c > 0.0)
+        .map(|((i, j), _)| {
+            // セルの位置を、カメラから見た向きに直す
+            let position = cell_position(i, j, n, lattice).extend(0.0) * scale;
+            (inverse * position).truncate()
+        })
+        .fold(None, |bounds, p| match bounds {
+            None => Some((p, p)),
+            Some((min, max)) => Some((min.min(p), max.max(p))),
+        })
+}
+
+/// Records the 2D camera in the [`ViewState`] saved on exit.
+fn track_camera(
+    camera: Query<(&Transform, &OrthographicProjection), With<FlatCamera>>,
+    mut view_state: ResMut<ViewState>,
+) {
+    let Ok((transform, projection)) = camera.get_single() else {
+        return;
+    };
+    let (zoom, pan) = (
+        projection.scale,
+        [transform.translation.x, transform.translation.y],
+    );
+    if view_state.zoom != zoom || view_state.pan != pan {
+        view_state.zoom = zoom;
+        view_state.pan = pan;
+    }
+}
+
+/// Activates the camera of the selected [`ViewMode`].
+fn switch_view(
+    view_mode: Res<ViewMode>,
+    mut flat_camera: Query<&mut Camera, (With<FlatCamera>, Without<SurfaceCamera>)>,
+    mut surface_camera: Query<&mut Camera, (With<SurfaceCamera>, Without<FlatCamera>)>,
+) {
+    if !view_mode.is_changed() {
+        return;
+    }
+    for mut camera in flat_camera.iter_mut() {
+        camera.is_active = *view_mode == ViewMode::Flat;
+    }
+    for mut camera in surface_camera.iter_mut() {
+        camera.is_active = *view_mode == ViewMode::Surface;
+    }
+}
+
+/// Resizes the cell texture and the quad when the grid size or the lattice of the field has
+/// changed.
+fn resize_grid(
+    field: Res<Field>,
+    mut grid: ResMut<CellGrid>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<HexGridMaterial>>,
+    mut quad: Query<&mut Transform, With<GridQuad>>,
+    coordinates: Res<Coordinates>,
+    cell_borders: Res<CellBorders>,
+) {
+    let (n, lattice) = {
         let field = field.0.read();
-        let max = field.cells.fold(0.0f32, |a, &b| a.max(b));
-        let min = field
-            .cells
-            .fold(max, |a, &b| if b > 0.0 { a.min(b) } else { a });
-        (&field.cells - min) / (max - min)
-    };
-
-    for (mut cell, mut visibility, mut material_handle) in query.iter_mut() {
-        let Cell(i, j, value) = &mut *cell;
-        let new_value = (new_values[[*i, *j]] * 254.0) as u8; // 0..=254。最終的には1..=255になる。0は透明になってしまうので1から始まるようにする。
-        if new_value > 0 {
-            if *value == new_value {
-                continue;
+        (field.cells.shape()[0], field.lattice)
+    };
+    if n == grid.n && lattice == grid.lattice {
+        return;
+    }
+    if n != grid.n {
+        images.insert(&grid.image, cells_image(n));
+    }
+    if let Some(material) = materials.get_mut(&grid.material) {
+        material.grid = HexGridUniform::new(n, coordinates.scale, *cell_borders, lattice);
+    }
+    for mut transform in quad.iter_mut() {
+        *transform = grid_transform(n, coordinates.scale, lattice);
+    }
+    grid.n = n;
+    grid.lattice = lattice;
+}
+
+/// Passes the [`CellBorders`] to the shader when they are changed.
+fn update_borders(
+    cell_borders: Res<CellBorders>,
+    grid: Res<CellGrid>,
+    mut materials: ResMut<Assets<HexGridMaterial>>,
+    coordinates: Res<Coordinates>,
+) {
+    if !cell_borders.is_changed() {
+        return;
+    }
+    if let Some(material) = materials.get_mut(&grid.material) {
+        material.grid = HexGridUniform::new(grid.n, coordinates.scale, *cell_borders, grid.lattice);
+    }
+}
+
+/// Writes the colors of the cells in the region the worker has changed since the last frame
+/// into the texture of the [`CellGrid`].
+///
+/// Every cell is redrawn only when the range of the values, and with it the color of every
+/// cell, has changed, or when the palette, the overlays or the grid have. A field other than
+/// the frozen mass chosen in [`crate::FieldInner::shown_layer`] changes everywhere, so it is redrawn
+/// whole each time the worker publishes it.
+#[allow(clippy::too_many_arguments)]
+fn update_visualization(
+    field: Res<Field>,
+    front_overlay: Res<FrontOverlay>,
+    cell_borders: Res<CellBorders>,
+    comparison: Res<Comparison>,
+    color_map: Res<ColorMap>,
+    grid: Res<CellGrid>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<HexGridMaterial>>,
+    mut drawn_range: Local<Option<(f32, f32)>>,
+    mut drawn_layer: Local<Option<(FieldLayer, u64)>>,
+) {
+    let mut field = field.0.write();
+    // リサイズ直後はテクスチャの作り直しを待つ。変更の記録は次のフレームまで残す
+    if field.cells.dim() != (grid.n, grid.n) || grid.n == 0 {
+        return;
+    }
+    let changed = field.changed.take();
+    let field = RwLockWriteGuard::downgrade(field);
+    let layer = field.layer.as_ref().filter(|layer| {
+        layer.layer == field.shown_layer && layer.values.dim() == field.cells.dim()
+    });
+    let layer_key = layer.map(|layer| (layer.layer, layer.step));
+    let (cells, range) = match layer {
+        Some(layer) => (&layer.values, layer.range),
+        None => (&field.cells, field.value_range),
+    };
+    let redraw_all = *drawn_range != Some(range)
+        || *drawn_layer != layer_key
+        || front_overlay.is_changed()
+        || cell_borders.is_changed()
+        || comparison.is_changed()
+        || color_map.is_changed()
+        || grid.is_changed();
+    let region = if redraw_all {
+        CellRegion {
+            min: [0, 0],
+            max: [grid.n - 1, grid.n - 1],
+        }
+    } else if let Some(changed) = changed {
+        // 成長前線は変わったセルの隣にも現れる
+        changed.expand(1, field.cells.dim())
+    } else {
+        return;
+    };
+    let Some(image) = images.get_mut(&grid.image) else {
+        return;
+    };
+    let palette: Vec<[u8; 4]> = (0..=255)
+        .map(|k| {
+            let color = color_map.color(k);
+            // 枠を描くときは薄いセルも見分けられるようにする
+            let color = if cell_borders.is_enabled && k > 0 {
+                color.with_alpha(sharpened_alpha(color.alpha()))
+            } else {
+                color
+            };
+            color.to_srgba().to_u8_array()
+        })
+        .collect();
+    let difference = comparison.shown_difference();
+    for j in region.min[1]..=region.max[1] {
+        for i in region.min[0]..=region.max[0] {
+            let color = cell_color(
+                cells,
+                field.lattice,
+                [i, j],
+                range,
+                // 成長前線は凍った質量についてのもの
+                front_overlay.is_enabled && layer.is_none(),
+                difference,
+            );
+            let offset = (j * grid.n + i) * 4;
+            image.data[offset..offset + 4].copy_from_slice(&color.rgba(&palette));
+        }
+    }
+    // 書き換えた画像はテクスチャごと作り直されるので、マテリアルにも結び直させる
+    materials.get_mut(&grid.material);
+    *drawn_range = Some(range);
+    *drawn_layer = layer_key;
+}
+
+/// How the cell at `index` is drawn, with the values scaled by the `(min, max)` of
+/// [`value_range`] as in [`normalize`].
+fn cell_color(
+    cells: &Array2<f32>,
+    lattice: Lattice,
+    index: [usize; 2],
+    (min, max): (f32, f32),
+    front: bool,
+    difference: Option<&Array2<f32>>,
+) -> CellColor {
+    if let Some(difference) = difference {
+        // 比較中は実行中の結晶の代わりに差を描く
+        return match difference.get(index) {
+            Some(&d) if !d.is_nan() => {
+                CellColor::Difference(((d + 1.0) / 2.0 * 255.0).round() as u8)
             }
-            *value = new_value;
-            let alpha = 255 - *value;
-            *material_handle = material_handles.handles[alpha as usize].clone();
-            *visibility = Visibility::Visible;
-        } else {
+            _ => CellColor::Hidden,
+        };
+    }
+    if front && is_growth_front(cells, lattice, index[0], index[1]) {
+        return CellColor::Front;
+    }
+    let new_value = (cells[index] - min) / (max - min);
+    let new_value = (new_value * 254.0) as u8; // 0..=254。最終的には1..=255になる。0は透明になってしまうので1から始まるようにする。
+    if new_value > 0 {
+        CellColor::Palette(255 - new_value)
+    } else {
+        CellColor::Hidden
+    }
+}
+
+/// Opacity `alpha` raised so that it starts at [`MIN_ALPHA_WITH_BORDERS`].
+fn sharpened_alpha(alpha: f32) -> f32 {
+    MIN_ALPHA_WITH_BORDERS + (1.0 - MIN_ALPHA_WITH_BORDERS) * alpha
+}
+
+/// Color of the `index`-th of the 256 entries of the palette for differences: blue for the
+/// most negative, white for zero and red for the most positive.
+fn diverging_color(index: u8) -> Color {
+    let t = index as f32 / 255.0 * 2.0 - 1.0;
+    let [blue, red] = [[0.23, 0.30, 0.75], [0.71, 0.02, 0.15]];
+    let end = if t < 0.0 { blue } else { red };
+    let [r, g, b] = [0, 1, 2].map(|c| 1.0 + (end[c] - 1.0) * t.abs());
+    Color::srgb(r, g, b)
+}
+
+/// Whether the cell at `(i, j)` is not frozen yet but has a frozen neighbor, where the crystal
+/// grows next.
+///
+/// Frozen cells are those with a positive mass in `cells`, and the neighbors those of
+/// `lattice`.
+fn is_growth_front(cells: &Array2<f32>, lattice: Lattice, i: usize, j: usize) -> bool {
+    let (rows, columns) = cells.dim();
+    cells[[i, j]] <= 0.0
+        && lattice.directions().iter().any(|&(di, dj)| {
+            let (ni, nj) = (i as isize + di, j as isize + dj);
+            // 格子の外は凍っていないものとして扱う
+            (0..rows as isize).contains(&ni)
+                && (0..columns as isize).contains(&nj)
+                && cells[[ni as usize, nj as usize]] > 0.0
+        })
+}
+
+/// What the surface mesh was last built from: step, grid size, lattice, height scale and mapping.
+type SurfaceKey = (u64, usize, Lattice, f32, HeightMapping);
+
+/// Rebuilds the surface mesh when the field has advanced.
+fn update_surface(
+    field: Res<Field>,
+    export_settings: Res<ExportSettings>,
+    coordinates: Res<Coordinates>,
+    view_mode: Res<ViewMode>,
+    mut surface: Query<(&Handle<Mesh>, &mut Visibility), With<Surface>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut built_for: Local<Option<SurfaceKey>>,
+) {
+    // STLと同じ縦横比・高さの対応で表示する
+    let height_scale = coordinates.scale * export_settings.z_scale / export_settings.xy_scale;
+    let mapping = export_settings.height_mapping;
+    let field = field.0.read();
+    let key = (
+        field.step,
+        field.cells.shape()[0],
+        field.lattice,
+        height_scale,
+        mapping,
+    );
+    if *built_for == Some(key) && !view_mode.is_changed() {
+        return;
+    }
+    *built_for = Some(key);
+    let (positions, normals, indices) =
+        surface_geometry(&field.cells, field.lattice, coordinates.scale, |c| {
+            mapping.height(c) * height_scale
+        });
+    drop(field);
+
+    for (handle, mut visibility) in surface.iter_mut() {
+        // 三角形が1つもないうちは空のメッシュを描かない
+        if indices.is_empty() {
             *visibility = Visibility::Hidden;
+            continue;
+        }
+        let Some(mesh) = meshes.get_mut(handle) else {
+            continue;
+        };
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions.clone());
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals.clone());
+        mesh.insert_indices(Indices::U32(indices.clone()));
+        *visibility = Visibility::Visible;
+    }
+}
+
+/// Vertices, normals and triangle indices of the top surface of the crystal.
+///
+/// The triangles are the same as the top of [`crate::stl::cells_to_triangles`], laid out on
+/// the grid of the 2D view. Only the bounding box of the crystal gets vertices.
+fn surface_geometry(
+    cells: &Array2<f32>,
+    lattice: Lattice,
+    scale: f32,
+    height_fn: impl Fn(f32) -> f32,
+) -> (Vec<[f32; 3]>, Vec<[f32; 3]>, Vec<u32>) {
+    let n = cells.shape()[0];
+    // 格子の2方向の1セル分の移動
+    let (along_i, along_j) = (
+        lattice.position(1.0, 0.0) * scale,
+        lattice.position(0.0, 1.0) * scale,
+    );
+    let (mut i0, mut i1, mut j0, mut j1) = (n, 0, n, 0);
+    for ((i, j), &c) in cells.indexed_iter() {
+        if c > 0.0 {
+            i0 = i0.min(i);
+            i1 = i1.max(i);
+            j0 = j0.min(j);
+            j1 = j1.max(j);
+        }
+    }
+    if i0 >= i1 || j0 >= j1 {
+        return (Vec::new(), Vec::new(), Vec::new());
+    }
+    let width = i1 - i0 + 1;
+    let height = |i: usize, j: usize| height_fn(cells[[i, j]]);
+    let index = |i: usize, j: usize| ((j - j0) * width + (i - i0)) as u32;
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    for j in j0..=j1 {
+        for i in i0..=i1 {
+            // 2D表示のセルと同じ位置
+            let position = cell_position(i, j, n, lattice) * scale;
+            positions.push(position.extend(height(i, j)).to_array());
+            // 格子の2方向の接ベクトルの外積を法線にする
+            let dh_di = (height((i + 1).min(n - 1), j) - height(i.saturating_sub(1), j)) / 2.0;
+            let dh_dj = (height(i, (j + 1).min(n - 1)) - height(i, j.saturating_sub(1))) / 2.0;
+            let normal = along_i
+                .extend(dh_di)
+                .cross(along_j.extend(dh_dj))
+                .normalize();
+            normals.push(normal.to_array());
+        }
+    }
+
+    let mut indices = Vec::new();
+    for j in j0..j1 {
+        for i in i0..i1 {
+            if cells[[i, j]] > 0.0 && cells[[i + 1, j]] > 0.0 && cells[[i, j + 1]] > 0.0 {
+                indices.extend([index(i, j), index(i + 1, j), index(i, j + 1)]);
+            }
+            if cells[[i + 1, j]] > 0.0 && cells[[i + 1, j + 1]] > 0.0 && cells[[i, j + 1]] > 0.0 {
+                indices.extend([index(i + 1, j), index(i + 1, j + 1), index(i, j + 1)]);
+            }
+        }
+    }
+    (positions, normals, indices)
+}
+
+/// Scales the cells so that the smallest positive mass maps to 0 and the largest to 1.
+pub fn normalize(cells: &Array2<f32>) -> Array2<f32> {
+    let (min, max) = value_range(cells);
+    (cells - min) / (max - min)
+}
+
+/// Smallest positive and largest mass of the cells, the ends of the scale of [`normalize`].
+pub fn value_range(cells: &Array2<f32>) -> (f32, f32) {
+    let max = cells.fold(0.0f32, |a, &b| a.max(b));
+    let min = cells.fold(max, |a, &b| if b > 0.0 { a.min(b) } else { a });
+    (min, max)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+
+    #[test]
+    fn cell_at_inverts_cell_position() {
+        let (n, scale) = (11, 2.0);
+        for (i, j) in [(0, 0), (5, 5), (10, 0), (3, 8), (10, 10)] {
+            for lattice in Lattice::ALL {
+                let center = cell_position(i, j, n, lattice) * scale;
+                assert_eq!(cell_at(center, n, scale, lattice), Some((i, j)));
+                // 六角形や正方形の内側にずれても同じセル
+                let inside = center + Vec2::new(0.4, -0.3) * scale;
+                assert_eq!(cell_at(inside, n, scale, lattice), Some((i, j)));
+            }
+        }
+        for lattice in Lattice::ALL {
+            let outside = cell_position(0, 0, n, lattice) * scale - Vec2::new(scale, 0.0);
+            assert_eq!(cell_at(outside, n, scale, lattice), None);
+        }
+    }
+
+    #[test]
+    fn cell_at_breaks_ties_like_the_shader() {
+        // 正方形の境目ちょうどの点は、hex_grid.wgsl の round と同じく偶数のセルに入る
+        let n = 11;
+        let (i, j) = (4.5 - 5.0, 7.5 - 5.0);
+        assert_eq!(
+            cell_at(Vec2::new(i, j), n, 1.0, Lattice::Square4),
+            Some((4, 8))
+        );
+    }
+
+    #[test]
+    fn ruler_measures_in_cell_spacings() {
+        let ruler = |end: (usize, usize), lattice| Ruler {
+            is_enabled: true,
+            start: Some(cell_position(5, 5, 11, lattice)),
+            end: Some(cell_position(end.0, end.1, 11, lattice)),
+        };
+        // 六角格子ではどの格子方向にも1セルが1間隔
+        for end in [(8, 5), (5, 8), (8, 2), (2, 8)] {
+            let distance = ruler(end, Lattice::Hex).distance().unwrap();
+            assert!((distance - 3.0).abs() < 1e-5, "{end:?} {distance}");
+        }
+        let diagonal = ruler((8, 9), Lattice::Square4).distance().unwrap();
+        assert!((diagonal - 5.0).abs() < 1e-5);
+        assert_eq!(Ruler::default().distance(), None);
+    }
+
+    #[test]
+    fn growth_axes_are_evenly_spaced() {
+        for (lattice, angle) in [
+            (Lattice::Hex, 60.0),
+            (Lattice::Square4, 90.0),
+            (Lattice::Square8, 45.0),
+        ] {
+            let axes = growth_axes(20, lattice, 2.0);
+            assert_eq!(axes.len(), lattice.directions().len());
+            let directions: Vec<Vec2> = axes.iter().map(|(start, end)| *end - *start).collect();
+            for k in 0..directions.len() {
+                let (a, b) = (directions[k], directions[(k + 1) % directions.len()]);
+                assert!(
+                    (a.angle_between(b).to_degrees() - angle).abs() < 1e-3,
+                    "{lattice:?}: {a} {b}"
+                );
+            }
+            // 六角格子では中心から端までの10歩
+            if lattice == Lattice::Hex {
+                assert!(directions.iter().all(|d| (d.length() - 20.0).abs() < 1e-3));
+            }
+        }
+    }
+
+    #[test]
+    fn crystal_bounds_follow_the_camera_rotation() {
+        let mut cells = Array2::zeros((4, 4));
+        assert!(crystal_bounds(&cells, Lattice::Hex, 1.0, Quat::IDENTITY).is_none());
+        // (2, 2) は原点に、(3, 2) はその右隣に描かれる
+        cells[[2, 2]] = 1.0;
+        cells[[3, 2]] = 1.0;
+        let (min, max) = crystal_bounds(&cells, Lattice::Hex, 1.0, Quat::IDENTITY).unwrap();
+        assert!(min.abs_diff_eq(Vec2::new(0.0, 0.0), 1e-5), "{min}");
+        assert!(max.abs_diff_eq(Vec2::new(1.0, 0.0), 1e-5), "{max}");
+        // 90° 回したカメラからは縦に並んで見える
+        let (min, max) =
+            crystal_bounds(&cells, Lattice::Hex, 1.0, Quat::from_rotation_z(FRAC_PI_2)).unwrap();
+        assert!(min.abs_diff_eq(Vec2::new(0.0, -1.0), 1e-5), "{min}");
+        assert!(max.abs_diff_eq(Vec2::new(0.0, 0.0), 1e-5), "{max}");
+    }
+
+    #[test]
+    fn changed_region_covers_the_differing_cells() {
+        let old = Array2::zeros((5, 5));
+        assert_eq!(CellRegion::of_changes(&old, &old), None);
+        let mut new = old.clone();
+        new[[1, 3]] = 1.0;
+        new[[2, 1]] = 0.5;
+        let region = CellRegion::of_changes(&old, &new).unwrap();
+        assert_eq!(region.min, [1, 1]);
+        assert_eq!(region.max, [2, 3]);
+        // 端では格子の内側で止まる
+        let expanded = region.expand(2, (5, 5));
+        assert_eq!(expanded.min, [0, 0]);
+        assert_eq!(expanded.max, [4, 4]);
+        // 大きさが変わったら全体
+        let resized = CellRegion::of_changes(&old, &Array2::zeros((3, 3))).unwrap();
+        assert_eq!(resized.min, [0, 0]);
+        assert_eq!(resized.max, [2, 2]);
+    }
+
+    #[test]
+    fn growth_front_surrounds_a_frozen_cell() {
+        let mut cells = Array2::zeros((5, 5));
+        cells[[2, 2]] = 1.0;
+        let front = Array2::from_shape_fn(cells.dim(), |(i, j)| {
+            is_growth_front(&cells, Lattice::Hex, i, j)
+        });
+        assert_eq!(front.iter().filter(|&&f| f).count(), 6);
+        for &(di, dj) in Lattice::Hex.directions() {
+            assert!(front[[(2 + di) as usize, (2 + dj) as usize]]);
         }
+        // 凍ったセル自身と、隣でない対角のセルは含まない
+        assert!(!front[[2, 2]]);
+        assert!(!front[[3, 3]]);
     }
 }